@@ -0,0 +1,185 @@
+#![no_std]
+
+// A constant product amm used as the reference dex in tests. Swaps move the sharded fungible
+// tokens of the pools, the deployer funds the reserves of the initial pools.
+
+use ft_main_io::{FTokenAction, FTokenEvent, LogicAction};
+use gstd::{debug, exec, msg, prelude::*, ActorId};
+use onchainquant_io::{AmmAction, AmmEvent, AmmPool};
+
+// fee of each swap, in 0.001
+const FEE: u128 = 3;
+
+// (token_a, token_b) => pool, token_a < token_b
+static mut POOLS: Option<HashMap<(String, String), AmmPool>> = None;
+// id of the last message sent to ft programs
+static mut TRANSACTION_ID: u64 = 0;
+
+fn pools() -> &'static mut HashMap<(String, String), AmmPool> {
+    unsafe { POOLS.get_or_insert(Default::default()) }
+}
+
+fn ordered(pool: AmmPool) -> AmmPool {
+    if pool.token_a < pool.token_b {
+        return pool;
+    }
+    AmmPool {
+        token_a: pool.token_b,
+        program_a: pool.program_b,
+        reserve_a: pool.reserve_b,
+        token_b: pool.token_a,
+        program_b: pool.program_a,
+        reserve_b: pool.reserve_a,
+    }
+}
+
+// (program, reserve) of `token_in` and of `token_out` in their pool
+fn sides(
+    token_in: &str,
+    token_out: &str,
+) -> Option<((ActorId, &'static mut u128), (ActorId, &'static mut u128))> {
+    let flipped = token_in > token_out;
+    let key = if flipped {
+        (token_out.to_string(), token_in.to_string())
+    } else {
+        (token_in.to_string(), token_out.to_string())
+    };
+    let AmmPool {
+        program_a,
+        reserve_a,
+        program_b,
+        reserve_b,
+        ..
+    } = pools().get_mut(&key)?;
+    if flipped {
+        Some(((*program_b, reserve_b), (*program_a, reserve_a)))
+    } else {
+        Some(((*program_a, reserve_a), (*program_b, reserve_b)))
+    }
+}
+
+async fn transfer(token: ActorId, sender: ActorId, recipient: ActorId, amount: u128) -> bool {
+    let transaction_id = unsafe {
+        TRANSACTION_ID += 1;
+        TRANSACTION_ID
+    };
+    let reply = match msg::send_for_reply_as::<_, FTokenEvent>(
+        token,
+        FTokenAction::Message {
+            transaction_id,
+            payload: LogicAction::Transfer {
+                sender,
+                recipient,
+                amount,
+            },
+        },
+        0,
+    ) {
+        Ok(future) => future.await,
+        Err(e) => {
+            debug!("send transfer to ft program failed {e}");
+            return false;
+        }
+    };
+    matches!(reply, Ok(FTokenEvent::Ok))
+}
+
+async fn add_liquidity(pool: AmmPool) -> Option<AmmPool> {
+    let pool = ordered(pool);
+    let key = (pool.token_a.clone(), pool.token_b.clone());
+    // the pool is created before the transfers, so a concurrent one can not use other programs
+    let current = pools().entry(key.clone()).or_insert_with(|| AmmPool {
+        reserve_a: 0,
+        reserve_b: 0,
+        ..pool.clone()
+    });
+    if current.program_a != pool.program_a || current.program_b != pool.program_b {
+        return None;
+    }
+    let source = msg::source();
+    let amm = exec::program_id();
+    if !transfer(pool.program_a, source, amm, pool.reserve_a).await {
+        return None;
+    }
+    if !transfer(pool.program_b, source, amm, pool.reserve_b).await {
+        if !transfer(pool.program_a, amm, source, pool.reserve_a).await {
+            debug!("refund {} {} failed", pool.reserve_a, pool.token_a);
+        }
+        return None;
+    }
+    let current = pools().get_mut(&key)?;
+    current.reserve_a += pool.reserve_a;
+    current.reserve_b += pool.reserve_b;
+    Some(current.clone())
+}
+
+async fn swap(token_in: &str, amount_in: u128, token_out: &str, min_out: u128) -> Option<u128> {
+    let ((program_in, reserve_in), (program_out, reserve_out)) = sides(token_in, token_out)?;
+    let amount_in_with_fee = amount_in * (1000 - FEE);
+    let amount_out = *reserve_out * amount_in_with_fee / (*reserve_in * 1000 + amount_in_with_fee);
+    if amount_out == 0 || amount_out < min_out {
+        return None;
+    }
+    // the reserves move first, other swaps may run while the transfers are pending
+    *reserve_in += amount_in;
+    *reserve_out -= amount_out;
+    let source = msg::source();
+    let amm = exec::program_id();
+    if transfer(program_in, source, amm, amount_in).await {
+        if transfer(program_out, amm, source, amount_out).await {
+            return Some(amount_out);
+        }
+        if !transfer(program_in, amm, source, amount_in).await {
+            debug!("refund {amount_in} {token_in} failed");
+        }
+    }
+    if let Some(((_, reserve_in), (_, reserve_out))) = sides(token_in, token_out) {
+        *reserve_in -= amount_in;
+        *reserve_out += amount_out;
+    }
+    None
+}
+
+#[gstd::async_main]
+async fn main() {
+    let action: AmmAction = msg::load().expect("can not decode an amm action!");
+    let reply = match action {
+        AmmAction::Swap {
+            token_in,
+            amount_in,
+            token_out,
+            min_out,
+        } => match swap(&token_in, amount_in, &token_out, min_out).await {
+            Some(amount_out) => AmmEvent::Swapped {
+                token_in,
+                amount_in,
+                token_out,
+                amount_out,
+            },
+            None => AmmEvent::SwapFailed,
+        },
+        AmmAction::AddLiquidity(pool) => match add_liquidity(pool).await {
+            Some(pool) => AmmEvent::LiquidityAdded(pool),
+            None => AmmEvent::AddLiquidityFailed,
+        },
+    };
+    msg::reply(reply, 0).expect("error in sending reply");
+}
+
+// initial pools
+#[no_mangle]
+extern "C" fn init() {
+    let init: Vec<AmmPool> = msg::load().expect("Unable to decode initial pools");
+    let pools = pools();
+    for pool in init {
+        let pool = ordered(pool);
+        let key = (pool.token_a.clone(), pool.token_b.clone());
+        let current = pools.entry(key).or_insert_with(|| AmmPool {
+            reserve_a: 0,
+            reserve_b: 0,
+            ..pool.clone()
+        });
+        current.reserve_a += pool.reserve_a;
+        current.reserve_b += pool.reserve_b;
+    }
+}
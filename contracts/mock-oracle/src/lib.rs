@@ -0,0 +1,48 @@
+#![no_std]
+
+// An oracle answering `OracleAction::GetPrice` with the prices pushed by `OracleAction::SetPrice`,
+// used by tests to drive exact prices.
+
+use gstd::{exec, msg, prelude::*};
+use onchainquant_io::{OracleAction, OracleEvent};
+
+// token => (price, updated at)
+static mut PRICES: Option<HashMap<String, (u64, u64)>> = None;
+
+#[no_mangle]
+extern "C" fn handle() {
+    let action: OracleAction = msg::load().expect("can not decode an oracle action!");
+    let prices = unsafe { PRICES.get_or_insert(Default::default()) };
+    let reply = match action {
+        OracleAction::GetPrice(token) => match prices.get(&token) {
+            Some((price, updated_at)) => OracleEvent::Price {
+                token,
+                price: *price,
+                updated_at: *updated_at,
+            },
+            None => OracleEvent::NoPrice(token),
+        },
+        OracleAction::SetPrice { token, price } => {
+            let updated_at = exec::block_timestamp();
+            prices.insert(token.clone(), (price, updated_at));
+            OracleEvent::Price {
+                token,
+                price,
+                updated_at,
+            }
+        }
+    };
+    msg::reply(reply, 0).expect("error in sending reply");
+}
+
+// initial prices, token => price
+#[no_mangle]
+extern "C" fn init() {
+    let init: Vec<(String, u64)> = msg::load().expect("Unable to decode initial prices");
+    let updated_at = exec::block_timestamp();
+    let prices = init
+        .into_iter()
+        .map(|(token, price)| (token, (price, updated_at)))
+        .collect();
+    unsafe { PRICES = Some(prices) };
+}
@@ -2,7 +2,7 @@
 
 use codec::{Decode, Encode};
 use gmeta::{In, InOut, Metadata};
-use gstd::prelude::*;
+use gstd::{prelude::*, ActorId};
 use scale_info::TypeInfo;
 
 #[derive(Default, Debug, Encode, Decode, TypeInfo)]
@@ -12,6 +12,307 @@ pub struct IOOnchainQuant {
     pub block_step: u32,
     pub block_next: u32,
     pub action_id: u64,
+    pub tokens: Vec<TokenConfig>,
+    pub price_source: PriceSource,
+    // the latest rounds skipped because of a bad price
+    pub price_alerts: Vec<PriceAlertMsg>,
+    // prices accepted in the latest rounds
+    pub price_rounds: Vec<PriceRound>,
+    // token => observations, oldest first
+    pub price_history: Vec<(String, Vec<PriceObservation>)>,
+    // in ms, 0 to trade at spot prices
+    pub twap_window: u64,
+    // token => time weighted average price of the last `twap_window` ms
+    pub twap: Vec<(String, u64)>,
+    // current state of the simulated market
+    pub market: MarketModelConfig,
+    pub dex: Option<ActorId>,
+    // in bps
+    pub max_slippage: u32,
+    // accounts that picked a strategy, the others use dca
+    pub strategies: Vec<(ActorId, StrategyConfig)>,
+    // accounts trading a grid with its state
+    pub grids: Vec<(ActorId, GridState)>,
+    pub dip_rules: Vec<(ActorId, DipRule)>,
+    pub sell_schedules: Vec<(ActorId, SellSchedule)>,
+    // pending stop-loss, trailing-stop and take-profit orders
+    pub orders: Vec<(ActorId, ConditionalOrder)>,
+    // accounts with their own dca plan, the others invest `r_invest_ration` every round
+    pub dca_plans: Vec<(ActorId, DcaPlan)>,
+    // latest changes of the global parameters, oldest first
+    pub config_log: Vec<ConfigChange>,
+    pub roles: Vec<(Role, Vec<ActorId>)>,
+    // zero once renounced
+    pub owner: ActorId,
+    pub pending_owner: Option<PendingOwner>,
+    pub shutdown: ShutdownStatus,
+}
+
+// phases of a shutdown, started by an admin with `Shutdown` and ended with `Terminate`
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum ShutdownStatus {
+    Active,
+    // no rounds nor investments since block `since`, the deposits are withdrawn or returned,
+    // `book_deposits` are tokens without ft program, `ReturnFunds` converts them to the quote
+    // token at their last accepted price, or what was paid for them without one,
+    // `pending_transfers` are still awaiting their replies
+    Closing {
+        since: u32,
+        open_deposits: u32,
+        book_deposits: u32,
+        pending_transfers: u32,
+    },
+    // every deposit left the program and no transfer is pending, an admin can terminate it
+    Settled {
+        since: u32,
+    },
+}
+
+// ownership offered to `owner` until it accepts it
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct PendingOwner {
+    pub owner: ActorId,
+    // last block the offer can be accepted in
+    pub expires_at: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum Role {
+    // grants and revokes roles
+    Admin,
+    // starts and stops the rounds, manages the config and the tokens
+    Operator,
+    // stops the rounds
+    Pauser,
+    // pushes prices with `FeedPrice`
+    PriceFeeder,
+}
+
+// a global parameter with its value
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum ConfigParam {
+    // in 0.000001, at most 1_000_000
+    InvestRation(u64),
+    // not 0, from the next scheduled round
+    BlockStep(u32),
+    PriceSource(PriceSource),
+    TwapWindow(u64),
+    // restarts the simulated market, correlation at most 1_000_000
+    Market(MarketModelConfig),
+    Dex(Option<ActorId>),
+    // in bps, at most 10_000
+    MaxSlippage(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct ConfigChange {
+    pub block: u32,
+    pub timestamp: u64,
+    pub previous: ConfigParam,
+    pub current: ConfigParam,
+}
+
+// quote token a user puts to work in a round
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum Budget {
+    // share of the quote token held, in 0.000001, kept within `min` and `max`
+    Ratio { ratio: u64, min: u128, max: u128 },
+    // the same amount every round, or what is left if less
+    Fixed(u128),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct DcaPlan {
+    pub budget: Budget,
+    // rounds to invest in, `None` for no end
+    pub installments: Option<u32>,
+    // rounds invested in so far, the plan is complete when it reaches `installments`
+    pub paid: u32,
+    // blocks between the rounds of the user, rounds run every `block_step` blocks at most
+    pub interval: u32,
+    pub start_block: u32,
+    // the user is skipped by the rounds before this block
+    pub next_block: u32,
+}
+
+// sent to a user whose dca plan invested its last installment
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct PlanCompletedMsg {
+    pub installments: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum OrderKind {
+    // sell once the price falls to `price`
+    StopLoss { price: u64 },
+    // sell once the price falls `distance` below the highest price since placing, in 0.000001
+    TrailingStop { distance: u64 },
+    // sell once the price rises to `price`
+    TakeProfit { price: u64 },
+}
+
+// sells `token` for the quote token when the price of a round meets `kind`
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct ConditionalOrder {
+    pub id: u64,
+    pub token: String,
+    pub kind: OrderKind,
+    // 0 to sell everything held
+    pub amount: u128,
+    // highest price since placing
+    pub peak: u64,
+}
+
+// sent to the owner of an order when it fires, the order stays if the sale failed
+#[derive(Debug, Encode, Decode, TypeInfo)]
+pub struct OrderFiredMsg {
+    pub order: ConditionalOrder,
+    pub price: u64,
+    pub amount: u128,
+    pub status: TradeStatus,
+}
+
+// sells a share of `token` for the quote token every round until `target` is sold
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct SellSchedule {
+    pub token: String,
+    // share of the token held sold each round, in 0.000001
+    pub ratio: u64,
+    // rounds priced below it sell nothing
+    pub floor: Option<u64>,
+    pub target: u128,
+    // sold so far, the schedule ends when it reaches `target`
+    pub sold: u128,
+}
+
+// scales the budget of a round up when prices are below a reference
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct DipRule {
+    pub reference: DipReference,
+    // (drop below the reference, budget multiplier), both in 0.000001,
+    // the deepest tier reached applies
+    pub tiers: Vec<(u64, u64)>,
+    // most of the quote token balance spent in a round, in 0.000001
+    pub max_budget: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum DipReference {
+    // time weighted average price of the last `window` ms
+    MovingAverage { window: u64 },
+    // what the user paid on average for the tokens held
+    AverageCost,
+}
+
+// investment strategy of a user, with its parameters
+#[derive(Debug, Default, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum StrategyConfig {
+    // the budget of each round buys the tokens by their weights
+    #[default]
+    Dca,
+    // trade back to the weights, the quote token weight included, once the value share
+    // of a token drifts from its weight by more than `band`, in 0.000001
+    Rebalance {
+        band: u64,
+    },
+    // buy or sell each round so that the value of the tradable tokens grows by `step`
+    // quote token per round from their value when the strategy is picked
+    ValueAveraging {
+        step: u128,
+    },
+    // `levels` prices evenly spread from `lower` to `upper`, `capital` quote token buys
+    // `token` when the price falls to a level and it is sold at the level above
+    Grid {
+        token: String,
+        lower: u64,
+        upper: u64,
+        levels: u32,
+        capital: u128,
+    },
+    // moving averages of the last `short` and `long` ms, a token gets its weighted share
+    // of the quote token when the short one crosses above the long one, and is sold
+    // when it crosses below
+    MaCrossover {
+        short: u64,
+        long: u64,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct GridState {
+    // (price of the level, amount bought at it), 0 if the level is not filled
+    pub levels: Vec<(u64, u128)>,
+    // price of the last round, the crossings are counted from it
+    pub last_price: u64,
+    // quote token received by the sales less the capital they used
+    pub profit: i128,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct PriceObservation {
+    pub block: u32,
+    pub timestamp: u64,
+    pub price: u64,
+}
+
+// price of `token` accepted in round `action_id`, the oracles answered for it are in `sources`
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct PriceRound {
+    pub action_id: u64,
+    pub token: String,
+    pub price: u64,
+    pub sources: Vec<ActorId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct TokenConfig {
+    pub name: String,
+    // sharded fungible token program
+    pub program_id: ActorId,
+    pub decimals: u8,
+    pub enabled: bool,
+    // max age of a price in ms before it is considered stale, 0 for no limit
+    pub max_age: u64,
+    // max change from the last accepted price, in 0.000001, 0 for no limit
+    pub max_deviation: u64,
+    // oracles whose median prices the token, the global price source is used if empty
+    pub oracles: Vec<ActorId>,
+    // answers needed from `oracles` to trust the median
+    pub min_quorum: u32,
 }
 
 #[derive(Encode, Decode, TypeInfo)]
@@ -19,14 +320,96 @@ pub enum OcqAction {
     Start,
     Stop,
     Act,
-    GasReserve { amount: u64, blocks: u32 },
+    GasReserve {
+        amount: u64,
+        blocks: u32,
+    },
     GasReserveDefault,
     // change AssetAllocationRatio, token => weight
     AssetAllocationRatio(Vec<(String, u32)>),
-    Invest { token: String, amount: u128 },
-    WithDraw { token: String, amount: u128 },
+    Invest {
+        token: String,
+        amount: u128,
+    },
+    WithDraw {
+        token: String,
+        amount: u128,
+    },
     Asset,
+    // admin only, exits the program once a shutdown settled every deposit
     Terminate,
+    // operator only, add a token that is not registered yet
+    RegisterToken(TokenConfig),
+    // operator only, replace the config of a registered token
+    UpdateToken(TokenConfig),
+    // operator only, remove a token nobody holds anymore
+    DelistToken(String),
+    // max slippage of the caller's swaps in bps, `None` to follow the global one
+    MaxSlippage(Option<u32>),
+    // investment strategy of the caller
+    SetStrategy(StrategyConfig),
+    // budget multiplier of the caller when prices dip, `None` to remove it
+    SetDipRule(Option<DipRule>),
+    // sell `ratio` of `token` held each round until `target` is sold, replaces
+    // the schedule of the caller for `token`
+    ScheduleSell {
+        token: String,
+        ratio: u64,
+        floor: Option<u64>,
+        target: u128,
+    },
+    CancelSell(String),
+    // the id of the order is replied in `OcqEvent::OrderPlaced`
+    PlaceOrder {
+        token: String,
+        kind: OrderKind,
+        amount: u128,
+    },
+    CancelOrder(u64),
+    // dca plan of the caller, the installments are counted from 0 again
+    SetDcaPlan {
+        budget: Budget,
+        installments: Option<u32>,
+        interval: u32,
+        start_block: u32,
+    },
+    // replied with `OcqEvent::DcaPlan`
+    DcaPlan,
+    // operator only, replied with `OcqEvent::ConfigChanged` once applied
+    UpdateConfig(ConfigParam),
+    // admin only
+    GrantRole {
+        role: Role,
+        account: ActorId,
+    },
+    // admin only, the last admin can not be revoked
+    RevokeRole {
+        role: Role,
+        account: ActorId,
+    },
+    // price feeder only, price in quote token units
+    FeedPrice {
+        token: String,
+        price: u64,
+    },
+    // owner only, offer the ownership to `owner` for `blocks` blocks, replaces a previous offer
+    ProposeOwner {
+        owner: ActorId,
+        blocks: u32,
+    },
+    // the proposed owner only, takes over the ownership with the roles of the previous owner
+    AcceptOwnership,
+    // owner only, leaves the program without owner, the roles of the owner are revoked,
+    // refused while the rounds run from its reservation or if no other admin is left
+    RenounceOwnership,
+    // admin only, stops the rounds and the investments for good, withdrawals go on
+    Shutdown,
+    // admin only during a shutdown, converts the book deposits to the quote token
+    // and transfers deposits back to their users, a batch per message
+    ReturnFunds,
+    // operator only, forget the last accepted price of the token,
+    // the next fresh price is accepted however far it moved
+    ResetPrice(String),
 }
 
 #[derive(Default, Debug, Encode, Decode, TypeInfo)]
@@ -36,6 +419,19 @@ pub struct TradeMsg {
     pub from_amount: u128,
     pub to_token: String,
     pub to_amount: u128,
+    // least amount accepted, the value at the round price less the max slippage
+    pub min_to_amount: u128,
+    pub status: TradeStatus,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum TradeStatus {
+    #[default]
+    Filled,
+    // the swap failed or missed `min_to_amount`, `from_amount` went back to the user
+    Failed,
 }
 
 #[derive(Default, Debug, Encode, Decode, TypeInfo)]
@@ -45,6 +441,31 @@ pub struct GasAlertMsg {
     pub msg: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum PriceAlertReason {
+    // older than the max age of the token
+    Stale,
+    // too far from the last accepted price
+    Deviation,
+    // fewer oracles answered than the token quorum
+    NoQuorum,
+}
+
+// a token skipped in round `action_id` because of its price
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct PriceAlertMsg {
+    pub action_id: u64,
+    pub token: String,
+    pub price: u64,
+    pub updated_at: u64,
+    pub last_price: u64,
+    pub reason: PriceAlertReason,
+}
+
 #[derive(Debug, PartialEq, Eq, Encode, Decode, TypeInfo)]
 #[codec(crate = gstd::codec)]
 #[scale_info(crate = gstd::scale_info)]
@@ -53,6 +474,65 @@ pub enum OcqEvent {
     // vector of (token name, amount)
     Asset(Vec<(String, u128)>),
     Success,
+    // amount of token transferred back to the user
+    WithDraw { token: String, amount: u128 },
+    // amount of token deposited after the ft program confirmed the transfer
+    Invest { token: String, amount: u128 },
+    // id of the new conditional order
+    OrderPlaced { id: u64 },
+    // dca plan of the caller, `None` if it follows the global ratio every round
+    DcaPlan(Option<DcaPlan>),
+    ConfigChanged(ConfigChange),
+    OwnershipProposed(PendingOwner),
+    OwnershipTransferred { previous: ActorId, current: ActorId },
+    // previous owner
+    OwnershipRenounced(ActorId),
+    Shutdown(ShutdownStatus),
+    // deposits transferred back by `ReturnFunds`, and the ones still in the program
+    FundsReturned { returned: u32, open_deposits: u32 },
+}
+
+// why an action was refused, nothing changed then
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum OcqError {
+    // the caller is not allowed to run the action
+    Unauthorized,
+    UnknownToken(String),
+    TokenDisabled(String),
+    TokenAlreadyRegistered(String),
+    // users still hold the token, it can only be disabled
+    TokenInUse(String),
+    // the token has no ft program to transfer it
+    NoFtProgram(String),
+    // the ft program refused the transfer
+    TransferFailed,
+    InsufficientBalance,
+    // empty or repeated tokens
+    InvalidWeights,
+    // out of range parameters of a strategy, plan, rule, order or config
+    InvalidParameters,
+    NotRunning,
+    AlreadyRunning,
+    // `Act` out of the scheduled block
+    NotScheduled,
+    NoReservation,
+    ReservationFailed,
+    UnknownOrder(u64),
+    // the caller has no sell schedule of the token
+    UnknownSchedule(String),
+    // the program needs an admin to manage the roles
+    LastAdmin,
+    // no ownership was offered
+    NoPendingOwner,
+    // the offered ownership was not accepted in time
+    OwnershipExpired,
+    // the action is not available once the shutdown started
+    ShuttingDown,
+    ShutdownNotStarted,
+    // users still have deposits in the program, or transfers are pending
+    FundsNotSettled,
 }
 
 #[derive(Debug, Decode, Encode, TypeInfo)]
@@ -62,13 +542,131 @@ pub struct InitConfig {
     // Regular Investment Ratio, in 0.000001
     pub r_invest_ration: u64,
     pub block_step: u32,
+    // tokens registered at init, more can be registered by the owner later
+    pub tokens: Vec<TokenConfig>,
+    pub price_source: PriceSource,
+    // trade at the time weighted average price of this many ms, 0 to trade at spot prices
+    pub twap_window: u64,
+    // model of the simulated price source, the testnet tokens are simulated if it has no token
+    pub market: MarketModelConfig,
+    // amm answering `AmmAction::Swap`, without one only tokens with no ft program are traded,
+    // converted at the round prices
+    pub dex: Option<ActorId>,
+    // max slippage of a swap in bps, users can set their own
+    pub max_slippage: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct MarketModelConfig {
+    pub tokens: Vec<SimulatedToken>,
+    // correlation between the tokens, in 0.000001
+    pub correlation: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct SimulatedToken {
+    pub name: String,
+    // current price in quote token units
+    pub price: u64,
+    // expected change per round, in 0.000001
+    pub drift: i64,
+    // standard deviation of the change per round, in 0.000001
+    pub volatility: u64,
+}
+
+// where the prices of each round come from, all prices are in quote token units
+#[derive(Debug, Default, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum PriceSource {
+    // prices of the simulated market model, for testnet only
+    #[default]
+    Simulated,
+    // an oracle program answering `OracleAction::GetPrice`
+    Oracle(ActorId),
+    // the last prices pushed by the price feeders
+    Feed,
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum OracleAction {
+    GetPrice(String),
+    // push a price, only for oracles fed by a feeder such as the mock oracle
+    SetPrice { token: String, price: u64 },
+}
+
+#[derive(Debug, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum OracleEvent {
+    // price in quote token units, updated_at is the block timestamp of the update
+    Price {
+        token: String,
+        price: u64,
+        updated_at: u64,
+    },
+    NoPrice(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub struct AmmPool {
+    pub token_a: String,
+    // sharded fungible token program of `token_a`
+    pub program_a: ActorId,
+    pub reserve_a: u128,
+    pub token_b: String,
+    pub program_b: ActorId,
+    pub reserve_b: u128,
+}
+
+#[derive(Debug, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum AmmAction {
+    // the caller approves `amount_in` to the amm first,
+    // fails if less than `min_out` of `token_out` would be received
+    Swap {
+        token_in: String,
+        amount_in: u128,
+        token_out: String,
+        min_out: u128,
+    },
+    // the reserves are pulled from the caller, who approves them to the amm first
+    AddLiquidity(AmmPool),
+}
+
+#[derive(Debug, PartialEq, Eq, Encode, Decode, TypeInfo)]
+#[codec(crate = gstd::codec)]
+#[scale_info(crate = gstd::scale_info)]
+pub enum AmmEvent {
+    Swapped {
+        token_in: String,
+        amount_in: u128,
+        token_out: String,
+        amount_out: u128,
+    },
+    // no pool for the pair, less than `min_out` would be received or a transfer failed,
+    // no token moved then
+    SwapFailed,
+    // reserves of the pool after adding liquidity
+    LiquidityAdded(AmmPool),
+    // the pool has other ft programs or a transfer failed, no token moved then
+    AddLiquidityFailed,
 }
 
 pub struct ProgramMetadata;
 
 impl Metadata for ProgramMetadata {
     type Init = In<InitConfig>;
-    type Handle = InOut<OcqAction, OcqEvent>;
+    type Handle = InOut<OcqAction, Result<OcqEvent, OcqError>>;
     type State = IOOnchainQuant;
     type Reply = ();
     type Others = ();
@@ -4,14 +4,68 @@ use gstd::{
 
 use onchainquant_io::*;
 
-use crate::price;
+use crate::{
+    dip, ft,
+    history::PriceHistory,
+    plan,
+    price::{self, MarketModel, TokenPrice},
+    strategy::{self, Strategy, StrategyContext, Trade, UserStrategy},
+    swap,
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct TokenInfo {
     pub name: String,
+    pub decimals: u8,
     // generated from decimals, if token decimal is 6, the multiples is 1_000_000
     pub multiples: u64,
-    // pub program_id: ActorId,
+    // sharded fungible token program of the token
+    pub program_id: ActorId,
+    // disabled tokens are neither invested nor traded, but can still be withdrawn
+    pub enabled: bool,
+    // in ms, 0 for no limit
+    pub max_age: u64,
+    // in 0.000001, 0 for no limit
+    pub max_deviation: u64,
+    // the median of these oracles prices the token instead of the global price source
+    pub oracles: Vec<ActorId>,
+    pub min_quorum: u32,
+}
+
+// `multiples` of the token must fit in a u64
+fn valid_decimals(decimals: u8) -> bool {
+    10u64.checked_pow(decimals as u32).is_some()
+}
+
+impl From<TokenConfig> for TokenInfo {
+    fn from(config: TokenConfig) -> Self {
+        TokenInfo {
+            name: config.name,
+            decimals: config.decimals,
+            multiples: 10u64.pow(config.decimals as u32),
+            program_id: config.program_id,
+            enabled: config.enabled,
+            max_age: config.max_age,
+            max_deviation: config.max_deviation,
+            oracles: config.oracles,
+            min_quorum: config.min_quorum,
+        }
+    }
+}
+
+impl From<&TokenInfo> for TokenConfig {
+    fn from(info: &TokenInfo) -> Self {
+        TokenConfig {
+            name: info.name.clone(),
+            program_id: info.program_id,
+            decimals: info.decimals,
+            enabled: info.enabled,
+            max_age: info.max_age,
+            max_deviation: info.max_deviation,
+            oracles: info.oracles.clone(),
+            min_quorum: info.min_quorum,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -19,6 +73,8 @@ pub struct TokenDeposit {
     // weight for Asset Allocation ratio,
     pub weight: u32,
     pub amount: u128,
+    // quote token paid for the amount held
+    pub cost: u128,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -26,6 +82,8 @@ pub struct OnchainQuant {
     // Regular Investment Ratio, in 0.000001
     pub r_invest_ration: u64,
     pub reservations: HashMap<ActorId, Reservation>,
+    // operator that started the rounds, they run from its reservation
+    pub runner: ActorId,
     pub token_info: HashMap<String, TokenInfo>,
     // account => (Token => Deposit)
     pub user_invest: HashMap<ActorId, HashMap<String, TokenDeposit>>,
@@ -33,8 +91,50 @@ pub struct OnchainQuant {
     pub block_next: u32,
     pub action_id: u64,
     pub owner: ActorId,
+    // id of the last message sent to ft programs
+    pub transaction_id: u64,
+    // ft transfers and swaps awaiting their replies, the program does not exit before they end
+    pub pending_transfers: u32,
+    pub price_source: PriceSource,
+    // token => last price that passed the checks
+    pub last_prices: HashMap<String, TokenPrice>,
+    pub price_alerts: Vec<PriceAlertMsg>,
+    // prices accepted in the latest rounds with their sources
+    pub price_rounds: Vec<PriceRound>,
+    pub price_history: HashMap<String, PriceHistory>,
+    // in ms, 0 to trade at spot prices
+    pub twap_window: u64,
+    // simulated price source
+    pub market: MarketModel,
+    // trades are swapped on this amm, without one only tokens with no ft program are traded,
+    // converted at the round prices
+    pub dex: Option<ActorId>,
+    // in bps, below the value at the spot price
+    pub max_slippage: u32,
+    // account => max slippage overriding the global one
+    pub user_slippage: HashMap<ActorId, u32>,
+    // account => strategy, dca if not set
+    pub strategies: HashMap<ActorId, UserStrategy>,
+    pub dip_rules: HashMap<ActorId, DipRule>,
+    // account => sell schedules, one per token
+    pub sell_schedules: HashMap<ActorId, Vec<SellSchedule>>,
+    pub orders: HashMap<ActorId, Vec<ConditionalOrder>>,
+    // id of the last conditional order placed
+    pub order_id: u64,
+    pub dca_plans: HashMap<ActorId, DcaPlan>,
+    pub config_log: Vec<ConfigChange>,
+    pub roles: BTreeMap<Role, BTreeSet<ActorId>>,
+    // token => last price pushed with the feeder that pushed it
+    pub fed_prices: HashMap<String, (ActorId, TokenPrice)>,
+    pub pending_owner: Option<PendingOwner>,
+    // block the shutdown started in
+    pub shutdown: Option<u32>,
+    // last deposit `ReturnFunds` tried
+    pub return_cursor: Option<(ActorId, String)>,
 }
-const RATION_MULTIPLES: u128 = 1_000_000;
+pub(crate) const RATION_MULTIPLES: u128 = 1_000_000;
+// slippage is in basis points
+const BPS_MULTIPLES: u128 = 10_000;
 static mut ONCHAIN_QUANT: Option<OnchainQuant> = None;
 
 static RESERVATION_AMOUNT: u64 = 50_000_000;
@@ -42,94 +142,1086 @@ static RESERVATION_AMOUNT: u64 = 50_000_000;
 static RESERVATION_TIME: u32 = 30 * 24 * 60 * 60 / 2;
 static ALERT_REMAIN_GAS: u64 = 5_000;
 static ALERT_REMAIN_BLOCKS: u32 = 2 * 24 * 60 * 60 / 2;
+// price alerts kept in state
+static MAX_PRICE_ALERTS: usize = 100;
+// accepted prices kept in state
+static MAX_PRICE_ROUNDS: usize = 100;
+// config changes kept in state
+static MAX_CONFIG_LOG: usize = 100;
+// deposits transferred back by a `ReturnFunds` message
+static MAX_RETURNS: usize = 20;
+// twap window of the state when trading at spot prices, an hour
+static STATE_TWAP_WINDOW: u64 = 60 * 60 * 1000;
 
 pub(crate) const BTC_NAME: &str = "ocqBTC";
 pub(crate) const DOT_NAME: &str = "ocqDOT";
 pub(crate) const USDT_NAME: &str = "ocqUSDT";
 
 impl OnchainQuant {
-    fn start(&mut self) {
+    // the caller must hold one of `roles`
+    fn only(&self, roles: &[Role]) -> Result<(), OcqError> {
+        let source = msg::source();
+        if !roles.iter().any(|role| self.has_role(*role, &source)) {
+            debug!("{:?} holds none of {:?}", source, roles);
+            return Err(OcqError::Unauthorized);
+        }
+        Ok(())
+    }
+
+    fn has_role(&self, role: Role, account: &ActorId) -> bool {
+        self.roles
+            .get(&role)
+            .map_or(false, |holders| holders.contains(account))
+    }
+
+    fn grant_role(&mut self, role: Role, account: ActorId) -> Result<(), OcqError> {
+        self.only(&[Role::Admin])?;
+        debug!("grant {:?} to {:?}", role, account);
+        self.roles.entry(role).or_default().insert(account);
+        Ok(())
+    }
+
+    fn revoke_role(&mut self, role: Role, account: ActorId) -> Result<(), OcqError> {
+        self.only(&[Role::Admin])?;
+        let holders = self.roles.entry(role).or_default();
+        if role == Role::Admin && holders.len() == 1 && holders.contains(&account) {
+            return Err(OcqError::LastAdmin);
+        }
+        debug!("revoke {:?} from {:?}", role, account);
+        holders.remove(&account);
+        Ok(())
+    }
+
+    fn only_owner(&self) -> Result<(), OcqError> {
         let source = msg::source();
         if self.owner != source {
-            debug!("only owner can start, {:?} is not owner", source);
-            return;
+            debug!("{:?} is not owner", source);
+            return Err(OcqError::Unauthorized);
+        }
+        Ok(())
+    }
+
+    fn propose_owner(&mut self, owner: ActorId, blocks: u32) -> Result<PendingOwner, OcqError> {
+        self.only_owner()?;
+        if owner == ActorId::zero() || owner == self.owner || blocks == 0 {
+            return Err(OcqError::InvalidParameters);
+        }
+        let pending = PendingOwner {
+            owner,
+            expires_at: exec::block_height().saturating_add(blocks),
+        };
+        debug!("ownership offered to {:?}", pending);
+        self.pending_owner = Some(pending.clone());
+        Ok(pending)
+    }
+
+    fn accept_ownership(&mut self) -> Result<OcqEvent, OcqError> {
+        let Some(pending) = &self.pending_owner else {
+            return Err(OcqError::NoPendingOwner);
+        };
+        if msg::source() != pending.owner {
+            return Err(OcqError::Unauthorized);
+        }
+        if exec::block_height() > pending.expires_at {
+            return Err(OcqError::OwnershipExpired);
+        }
+        let previous = self.owner;
+        let current = pending.owner;
+        self.hand_over(current);
+        Ok(OcqEvent::OwnershipTransferred { previous, current })
+    }
+
+    fn renounce_ownership(&mut self) -> Result<OcqEvent, OcqError> {
+        self.only_owner()?;
+        // nobody would own the reservation running the rounds
+        if self.block_next != 0 && self.runner == self.owner {
+            return Err(OcqError::AlreadyRunning);
+        }
+        let previous = self.owner;
+        let other_admin = self.roles.get(&Role::Admin).map_or(false, |admins| {
+            admins.iter().any(|admin| *admin != previous)
+        });
+        if !other_admin {
+            return Err(OcqError::LastAdmin);
+        }
+        self.hand_over(ActorId::zero());
+        Ok(OcqEvent::OwnershipRenounced(previous))
+    }
+
+    // the roles and the gas reservation of the owner follow the ownership,
+    // the reservation is released when nobody takes over
+    fn hand_over(&mut self, owner: ActorId) {
+        let previous = self.owner;
+        debug!("ownership handed over from {:?} to {:?}", previous, owner);
+        for holders in self.roles.values_mut() {
+            if holders.remove(&previous) && owner != ActorId::zero() {
+                holders.insert(owner);
+            }
+        }
+        if let Some(reservation) = self.reservations.remove(&previous) {
+            let released = if owner == ActorId::zero() {
+                Some(reservation)
+            } else {
+                self.reservations.insert(owner, reservation)
+            };
+            if let Some(resv) = released {
+                if let Ok(gas) = resv.unreserve() {
+                    debug!("release {gas} gas");
+                }
+            }
+        }
+        if self.runner == previous {
+            self.runner = owner;
+        }
+        self.owner = owner;
+        self.pending_owner = None;
+    }
+
+    fn feed_price(&mut self, token: String, price: u64) -> Result<(), OcqError> {
+        self.only(&[Role::PriceFeeder])?;
+        self.check_tradable(&token)?;
+        if price == 0 {
+            return Err(OcqError::InvalidParameters);
+        }
+        let price = TokenPrice {
+            price,
+            updated_at: exec::block_timestamp(),
+        };
+        self.fed_prices.insert(token, (msg::source(), price));
+        Ok(())
+    }
+
+    // `Act` is only sent by the program to itself
+    async fn act(&mut self) -> Result<(), OcqError> {
+        if msg::source() != exec::program_id() {
+            return Err(OcqError::Unauthorized);
+        }
+        self.action().await
+    }
+
+    async fn start(&mut self) -> Result<(), OcqError> {
+        self.only(&[Role::Operator])?;
+        if self.shutdown.is_some() {
+            return Err(OcqError::ShuttingDown);
         }
         let block = exec::block_height();
-        if self.block_next >= block {
+        if self.block_next != 0 && self.block_next >= block {
             debug!(
                 "already start, schedule in {}, should stop before start",
                 self.block_next
             );
+            return Err(OcqError::AlreadyRunning);
         }
         // not start, this will triger a start
-        self.block_next = exec::block_height();
-        self.action();
+        self.block_next = block;
+        self.runner = msg::source();
+        self.action().await
     }
 
-    fn stop(&mut self) {
-        let source = msg::source();
-        if self.owner != source {
-            debug!("only owner can stop, {:?} is not owner", source);
-            return;
+    fn stop(&mut self) -> Result<(), OcqError> {
+        self.only(&[Role::Operator, Role::Pauser])?;
+        if self.block_next == 0 {
+            return Err(OcqError::NotRunning);
         }
         self.block_next = 0;
+        Ok(())
     }
 
-    fn quant(&mut self) {
-        let prices = price::get_price();
-        for (user, token_deposit) in self.user_invest.iter_mut() {
-            let who = if user == &exec::program_id() {
+    // keep the prices that are fresh and close enough to the last accepted ones,
+    // the owner is alerted about every token skipped in this round
+    fn checked_prices(
+        &mut self,
+        action_id: u64,
+        prices: HashMap<String, TokenPrice>,
+    ) -> HashMap<String, u64> {
+        let now = exec::block_timestamp();
+        let mut dest = HashMap::new();
+        for (token, price) in prices {
+            let Some(info) = self.token_info.get(&token) else {
+                continue;
+            };
+            let last_price = self.last_prices.get(&token).map_or(0, |p| p.price);
+            let reason = if info.max_age > 0 && now.saturating_sub(price.updated_at) > info.max_age
+            {
+                Some(PriceAlertReason::Stale)
+            } else if info.max_deviation > 0
+                && last_price > 0
+                && (price.price.abs_diff(last_price) as u128 * RATION_MULTIPLES
+                    / last_price as u128)
+                    > info.max_deviation as u128
+            {
+                Some(PriceAlertReason::Deviation)
+            } else {
+                None
+            };
+            match reason {
+                None => {
+                    self.last_prices.insert(token.clone(), price);
+                    dest.insert(token, price.price);
+                }
+                Some(reason) => {
+                    debug!(
+                        "skip {token} in action {action_id}, price {:?} {:?}",
+                        price, reason
+                    );
+                    self.alert_price(PriceAlertMsg {
+                        action_id,
+                        token,
+                        price: price.price,
+                        updated_at: price.updated_at,
+                        last_price,
+                        reason,
+                    });
+                }
+            }
+        }
+        dest
+    }
+
+    // a real move beyond `max_deviation` is skipped until the reference is reset
+    fn reset_price(&mut self, token: String) -> Result<(), OcqError> {
+        self.only(&[Role::Operator])?;
+        if !self.token_info.contains_key(&token) {
+            return Err(OcqError::UnknownToken(token));
+        }
+        debug!("reset the last price of {token}");
+        self.last_prices.remove(&token);
+        Ok(())
+    }
+
+    fn alert_price(&mut self, alert: PriceAlertMsg) {
+        if let Err(e) = msg::send(self.owner, alert.clone(), 0) {
+            debug!("send price alert failed {e}");
+        }
+        if self.price_alerts.len() >= MAX_PRICE_ALERTS {
+            self.price_alerts.remove(0);
+        }
+        self.price_alerts.push(alert);
+    }
+
+    // prices of this round, a token with its own oracles takes the median of their answers,
+    // the other tokens are priced by the global price source
+    async fn round_prices(&mut self, action_id: u64) -> HashMap<String, u64> {
+        let mut tokens = Vec::new();
+        let mut own_oracles = Vec::new();
+        for info in self
+            .token_info
+            .values()
+            .filter(|info| info.enabled && info.name != USDT_NAME)
+        {
+            if info.oracles.is_empty() {
+                tokens.push(info.name.clone());
+            } else {
+                own_oracles.push(info.clone());
+            }
+        }
+        let mut prices = price::get_price(
+            &self.price_source,
+            &tokens,
+            &mut self.market,
+            &self.fed_prices,
+        )
+        .await;
+        let mut sources: HashMap<String, Vec<ActorId>> = prices
+            .keys()
+            .map(|token| {
+                let source = match self.price_source {
+                    PriceSource::Oracle(oracle) => vec![oracle],
+                    PriceSource::Feed => self
+                        .fed_prices
+                        .get(token)
+                        .map(|(feeder, _)| vec![*feeder])
+                        .unwrap_or_default(),
+                    PriceSource::Simulated => vec![],
+                };
+                (token.clone(), source)
+            })
+            .collect();
+        for info in own_oracles {
+            let quotes = price::collect_prices(&info.oracles, &info.name, info.max_age).await;
+            // a single answer is the least to trust
+            if quotes.len() < info.min_quorum.max(1) as usize {
+                debug!(
+                    "{} of {} oracles answered {} price, quorum is {}",
+                    quotes.len(),
+                    info.oracles.len(),
+                    info.name,
+                    info.min_quorum
+                );
+                let last_price = self.last_prices.get(&info.name).map_or(0, |p| p.price);
+                self.alert_price(PriceAlertMsg {
+                    action_id,
+                    token: info.name,
+                    price: 0,
+                    updated_at: 0,
+                    last_price,
+                    reason: PriceAlertReason::NoQuorum,
+                });
+                continue;
+            }
+            if let Some(price) = price::median(&quotes) {
+                sources.insert(
+                    info.name.clone(),
+                    quotes.iter().map(|(oracle, _)| *oracle).collect(),
+                );
+                prices.insert(info.name, price);
+            }
+        }
+        let prices = self.checked_prices(action_id, prices);
+        for (token, price) in prices.iter() {
+            if self.price_rounds.len() >= MAX_PRICE_ROUNDS {
+                self.price_rounds.remove(0);
+            }
+            self.price_rounds.push(PriceRound {
+                action_id,
+                token: token.clone(),
+                price: *price,
+                sources: sources.remove(token).unwrap_or_default(),
+            });
+            self.price_history
+                .entry(token.clone())
+                .or_default()
+                .push(PriceObservation {
+                    block: exec::block_height(),
+                    timestamp: exec::block_timestamp(),
+                    price: *price,
+                });
+        }
+        prices
+    }
+
+    // prices to trade at, the twap of the window if one is configured
+    fn trade_prices(&self, spot: HashMap<String, u64>) -> HashMap<String, u64> {
+        if self.twap_window == 0 {
+            return spot;
+        }
+        let now = exec::block_timestamp();
+        spot.into_iter()
+            .map(|(token, price)| {
+                let twap = self
+                    .price_history
+                    .get(&token)
+                    .and_then(|history| history.twap(self.twap_window, now))
+                    .unwrap_or(price);
+                (token, twap)
+            })
+            .collect()
+    }
+
+    async fn quant(&mut self, action_id: u64) {
+        // swaps are bounded by the spot prices, whatever prices the trades are planned at
+        let spot = self.round_prices(action_id).await;
+        let prices = self.trade_prices(spot.clone());
+        // registered and enabled tokens with a price in this round
+        let prices: HashMap<String, u64> = prices
+            .into_iter()
+            .filter(|(k, _v)| {
+                k != USDT_NAME && self.token_info.get(k).map_or(false, |info| info.enabled)
+            })
+            .collect();
+        let block = exec::block_height();
+        let users: Vec<ActorId> = self.user_invest.keys().copied().collect();
+        for user in users {
+            let who = if user == exec::program_id() {
                 "********** contract ********* ".to_string()
             } else {
                 hex::encode(user.as_ref())
             };
-            let weight_sum: u32 = token_deposit
-                .iter()
-                .filter(|(k, _v)| {
-                    k.as_str() != USDT_NAME && (k.as_str() == BTC_NAME || k.as_str() == DOT_NAME)
-                })
-                .map(|(_k, v)| v.weight)
-                .sum();
-            let usdt = token_deposit.entry_ref(USDT_NAME).or_default();
-            let budget = usdt.amount * self.r_invest_ration as u128 / RATION_MULTIPLES;
-            usdt.amount -= budget;
-            for (k, token) in token_deposit
-                .iter_mut()
-                .filter(|(k, _v)| k.as_str() != USDT_NAME)
+            self.check_orders(&user, &who, &prices, &spot).await;
+            if !self.is_due(&user, block) {
+                continue;
+            }
+            let (trades, funded) = self.plan(&user, &prices);
+            let mut invested = false;
+            for trade in trades {
+                let received = self
+                    .execute_trade(&user, &who, trade.clone(), &prices, &spot)
+                    .await;
+                invested |= funded && received.is_some() && trade.from_token == USDT_NAME;
+                if let Some(strategy) = self.strategies.get_mut(&user) {
+                    strategy.settle(&trade, received);
+                }
+            }
+            // an installment is a round the budget bought something in
+            if invested {
+                self.pay_installment(&user);
+            }
+            self.scheduled_sells(&user, &who, &prices, &spot).await;
+            if let Some(dca_plan) = self.dca_plans.get_mut(&user) {
+                dca_plan.next_block = block.saturating_add(dca_plan.interval);
+            }
+            self.log_asset(&user, &who, &prices);
+        }
+    }
+
+    // users without a plan run every round
+    fn is_due(&self, user: &ActorId, block: u32) -> bool {
+        self.dca_plans
+            .get(user)
+            .map_or(true, |dca_plan| block >= dca_plan.next_block)
+    }
+
+    // trades the strategy of `user` plans for this round,
+    // and whether their buys are paid by the budget of the round
+    fn plan(&mut self, user: &ActorId, prices: &HashMap<String, u64>) -> (Vec<Trade>, bool) {
+        let Some(portfolio) = self.user_invest.get(user) else {
+            return (vec![], false);
+        };
+        let usdt = portfolio.get(USDT_NAME).map_or(0, |d| d.amount);
+        let mut budget = match self.dca_plans.get(user) {
+            Some(dca_plan) => plan::budget(dca_plan, usdt),
+            None => usdt * self.r_invest_ration as u128 / RATION_MULTIPLES,
+        };
+        if let Some(rule) = self.dip_rules.get(user).filter(|_rule| budget > 0) {
+            budget = self.dip_budget(rule, portfolio, prices, budget, usdt);
+        }
+        let ctx = StrategyContext {
+            portfolio,
+            prices,
+            token_info: &self.token_info,
+            history: &self.price_history,
+            now: exec::block_timestamp(),
+            budget,
+        };
+        // only the users that picked a strategy are kept
+        let mut default = UserStrategy::default();
+        let strategy = self.strategies.get_mut(user).unwrap_or(&mut default);
+        (strategy.plan(&ctx), budget > 0 && strategy.spends_budget())
+    }
+
+    // count a round invested in, the user is notified when the plan completes
+    fn pay_installment(&mut self, user: &ActorId) {
+        let Some(dca_plan) = self.dca_plans.get_mut(user) else {
+            return;
+        };
+        dca_plan.paid += 1;
+        if plan::is_complete(dca_plan) {
+            debug!("dca plan of {:?} is complete", user);
+            let _ = msg::send(
+                *user,
+                PlanCompletedMsg {
+                    installments: dca_plan.paid,
+                }
+                .encode(),
+                0,
+            );
+        }
+    }
+
+    // `budget` scaled by the dip multiplier of each token averaged by the weights,
+    // never more than the max budget share of the quote token balance
+    fn dip_budget(
+        &self,
+        rule: &DipRule,
+        portfolio: &HashMap<String, TokenDeposit>,
+        prices: &HashMap<String, u64>,
+        budget: u128,
+        usdt: u128,
+    ) -> u128 {
+        let now = exec::block_timestamp();
+        let mut weight_sum = 0u128;
+        let mut weighted = 0u128;
+        for (k, deposit) in portfolio.iter().filter(|(_k, d)| d.weight > 0) {
+            let Some(price) = prices.get(k) else {
+                continue;
+            };
+            let reference = match rule.reference {
+                DipReference::MovingAverage { window } => self
+                    .price_history
+                    .get(k)
+                    .and_then(|history| history.twap(window, now)),
+                DipReference::AverageCost => self
+                    .token_info
+                    .get(k)
+                    .filter(|_info| deposit.amount > 0)
+                    .map(|info| (deposit.cost * info.multiples as u128 / deposit.amount) as u64),
+            }
+            .unwrap_or_default();
+            let multiplier = dip::multiplier(rule, dip::drop(*price, reference));
+            debug!("{k} price {price} reference {reference} budget multiplier {multiplier}");
+            weight_sum += deposit.weight as u128;
+            weighted += deposit.weight as u128 * multiplier as u128;
+        }
+        if weight_sum == 0 {
+            return budget;
+        }
+        let scaled = budget * weighted / weight_sum / RATION_MULTIPLES;
+        // the cap limits what the dip adds, never the budget without it
+        let cap = usdt * rule.max_budget as u128 / RATION_MULTIPLES;
+        scaled.min(cap.max(budget))
+    }
+
+    // sell for the orders of `user` the prices of the round meet, the owner is notified
+    // of each fired order, which is removed once the sale went through
+    async fn check_orders(
+        &mut self,
+        user: &ActorId,
+        who: &str,
+        prices: &HashMap<String, u64>,
+        spot: &HashMap<String, u64>,
+    ) {
+        let Some(orders) = self.orders.get_mut(user) else {
+            return;
+        };
+        let mut fired = Vec::new();
+        for order in orders.iter_mut() {
+            let Some(price) = prices.get(&order.token).copied() else {
+                continue;
+            };
+            order.peak = order.peak.max(price);
+            let hit = match order.kind {
+                OrderKind::StopLoss { price: stop } => price <= stop,
+                OrderKind::TrailingStop { distance } => {
+                    (price as u128)
+                        <= order.peak as u128 * (RATION_MULTIPLES - distance as u128)
+                            / RATION_MULTIPLES
+                }
+                OrderKind::TakeProfit { price: target } => price >= target,
+            };
+            if hit {
+                fired.push((order.clone(), price));
+            }
+        }
+        for (order, price) in fired {
+            // orders can be cancelled while an earlier one sells
+            if !self.has_order(user, order.id) {
+                continue;
+            }
+            let held = self
+                .user_invest
+                .get(user)
+                .and_then(|m| m.get(&order.token))
+                .map_or(0, |d| d.amount);
+            if held == 0 {
+                debug!(
+                    "{} order {} fired with no {} held",
+                    who, order.id, order.token
+                );
+                continue;
+            }
+            let amount = if order.amount == 0 {
+                held
+            } else {
+                order.amount.min(held)
+            };
+            debug!(
+                "{} order {} fired at {}, sell {}",
+                who, order.id, price, amount
+            );
+            let trade = Trade {
+                from_token: order.token.clone(),
+                from_amount: amount,
+                to_token: USDT_NAME.to_string(),
+                tag: None,
+            };
+            let status = match self.execute_trade(user, who, trade, prices, spot).await {
+                Some(_) => TradeStatus::Filled,
+                None => TradeStatus::Failed,
+            };
+            let _ = msg::send(
+                *user,
+                OrderFiredMsg {
+                    order: order.clone(),
+                    price,
+                    amount,
+                    status,
+                }
+                .encode(),
+                0,
+            );
+            if status == TradeStatus::Filled {
+                self.remove_order(user, order.id);
+            }
+        }
+    }
+
+    fn has_order(&self, user: &ActorId, id: u64) -> bool {
+        self.orders
+            .get(user)
+            .map_or(false, |orders| orders.iter().any(|order| order.id == id))
+    }
+
+    fn remove_order(&mut self, user: &ActorId, id: u64) {
+        let Some(orders) = self.orders.get_mut(user) else {
+            return;
+        };
+        orders.retain(|order| order.id != id);
+        if orders.is_empty() {
+            self.orders.remove(user);
+        }
+    }
+
+    // sell the share of each schedule of `user` priced above its floor,
+    // the schedules that reached their target are removed
+    async fn scheduled_sells(
+        &mut self,
+        user: &ActorId,
+        who: &str,
+        prices: &HashMap<String, u64>,
+        spot: &HashMap<String, u64>,
+    ) {
+        let Some(schedules) = self.sell_schedules.get(user) else {
+            return;
+        };
+        let tokens: Vec<String> = schedules.iter().map(|s| s.token.clone()).collect();
+        for token in tokens {
+            // schedules can be cancelled or replaced while an earlier one sells
+            let Some(schedule) = self
+                .sell_schedules
+                .get(user)
+                .and_then(|schedules| schedules.iter().find(|s| s.token == token))
+                .cloned()
+            else {
+                continue;
+            };
+            let Some(price) = prices.get(&token) else {
+                continue;
+            };
+            if schedule.floor.map_or(false, |floor| *price < floor) {
+                debug!("{} {} price {} is below the floor", who, token, price);
+                continue;
+            }
+            let held = self
+                .user_invest
+                .get(user)
+                .and_then(|m| m.get(&token))
+                .map_or(0, |d| d.amount);
+            let amount = (held * schedule.ratio as u128 / RATION_MULTIPLES)
+                .min(schedule.target - schedule.sold);
+            if amount == 0 {
+                continue;
+            }
+            let trade = Trade {
+                from_token: token.clone(),
+                from_amount: amount,
+                to_token: USDT_NAME.to_string(),
+                tag: None,
+            };
+            if self
+                .execute_trade(user, who, trade, prices, spot)
+                .await
+                .is_none()
             {
-                let price = prices.get(k).unwrap();
-                // budget * (weight / weight_sum) / price * btc_multiples
-                let budget = budget * token.weight as u128 / weight_sum as u128;
-                let info = self.token_info.get(k).unwrap();
-                let buy = budget * info.multiples as u128 / *price as u128;
-                token.amount += buy;
-                debug!("{} Spend {} USDT, buy {} {}", who, budget, buy, k);
-                let _ = msg::send(
-                    *user,
-                    TradeMsg {
-                        time: exec::block_timestamp(),
-                        from_token: USDT_NAME.to_string(),
-                        from_amount: budget,
-                        to_token: k.to_string(),
-                        to_amount: buy,
-                    }
-                    .encode(),
-                    0,
+                continue;
+            }
+            // counted on the schedule the sale was made for, if it is still there
+            let Some(schedules) = self.sell_schedules.get_mut(user) else {
+                continue;
+            };
+            let Some(live) = schedules.iter_mut().find(|s| **s == schedule) else {
+                continue;
+            };
+            live.sold += amount;
+            if live.sold >= live.target {
+                debug!("{} sold {} {}", who, live.sold, token);
+                schedules.retain(|s| s.sold < s.target);
+                if schedules.is_empty() {
+                    self.sell_schedules.remove(user);
+                }
+            }
+        }
+    }
+
+    // amount of `to` worth `amount` of `from` at the prices of the round
+    fn quote(
+        &self,
+        from: &str,
+        amount: u128,
+        to: &str,
+        prices: &HashMap<String, u64>,
+    ) -> Option<u128> {
+        strategy::convert(&self.token_info, prices, from, amount, to)
+    }
+
+    // the dex pulls the approved `amount_in` from this program and pays `token_out` back to it
+    async fn swap_on_dex(
+        &mut self,
+        dex: ActorId,
+        token_in: &str,
+        amount_in: u128,
+        token_out: &str,
+        min_out: u128,
+    ) -> Option<u128> {
+        let (Some(program_in), Some(_)) = (self.ft_program(token_in), self.ft_program(token_out))
+        else {
+            debug!("{token_in} or {token_out} has no ft program to swap");
+            return None;
+        };
+        let transaction_id = self.next_transaction_id();
+        ft::approve(&program_in, transaction_id, &dex, amount_in)
+            .await
+            .ok()?;
+        let received = swap::swap(&dex, token_in, amount_in, token_out, min_out).await;
+        if received.is_none() {
+            // nothing moved, the dex keeps no allowance
+            let transaction_id = self.next_transaction_id();
+            let _ = ft::approve(&program_in, transaction_id, &dex, 0).await;
+        }
+        received
+    }
+
+    // swap on the dex, or convert tokens without an ft program at the prices of the round,
+    // the spent amount goes back to the user if the swap fails, `None` then
+    async fn execute_trade(
+        &mut self,
+        user: &ActorId,
+        who: &str,
+        trade: Trade,
+        prices: &HashMap<String, u64>,
+        spot: &HashMap<String, u64>,
+    ) -> Option<u128> {
+        let Trade {
+            from_token,
+            from_amount,
+            to_token,
+            ..
+        } = trade;
+        match self
+            .user_invest
+            .get_mut(user)
+            .and_then(|m| m.get_mut(&from_token))
+        {
+            Some(deposit) if deposit.amount >= from_amount => deposit.amount -= from_amount,
+            _ => {
+                debug!(
+                    "{} has not enough {} to spend {}",
+                    who, from_token, from_amount
                 );
+                return None;
             }
-            let mut total_asset = 0u128;
-            for (k, token) in token_deposit {
-                debug!("{} {} {}", who, k, token.amount);
-                if k == USDT_NAME {
-                    total_asset += token.amount;
-                } else {
-                    let info = self.token_info.get(k).unwrap();
-                    let price = prices.get(k).unwrap();
-                    total_asset += *price as u128 * token.amount / info.multiples as u128;
+        }
+        let expected = self
+            .quote(&from_token, from_amount, &to_token, prices)
+            .unwrap_or_default();
+        // a swap gets at least the value at the spot price, less the allowed slippage,
+        // the books convert at the prices of the round
+        let dex = self.dex;
+        let reference = match dex {
+            Some(_) => self
+                .quote(&from_token, from_amount, &to_token, spot)
+                .unwrap_or_default(),
+            None => expected,
+        };
+        let slippage = self
+            .user_slippage
+            .get(user)
+            .copied()
+            .unwrap_or(self.max_slippage) as u128;
+        let min_to_amount = reference * (BPS_MULTIPLES - slippage) / BPS_MULTIPLES;
+        let received = match dex {
+            _ if expected == 0 => None,
+            Some(dex) => {
+                self.pending_transfers += 1;
+                let received = self
+                    .swap_on_dex(
+                        dex,
+                        &from_token,
+                        from_amount,
+                        &to_token,
+                        min_to_amount.max(1),
+                    )
+                    .await;
+                self.pending_transfers -= 1;
+                received
+            }
+            // the books can not hand out real tokens, they only hold the quote token paid
+            None if [&from_token, &to_token]
+                .into_iter()
+                .any(|token| token != USDT_NAME && self.ft_program(token).is_some()) =>
+            {
+                debug!("{who} needs a dex to trade {from_token} for {to_token}");
+                None
+            }
+            None => Some(expected),
+        };
+        // value paid at the round price, the cost of what is bought
+        let paid = self
+            .quote(&from_token, from_amount, USDT_NAME, prices)
+            .unwrap_or_default();
+        let token_deposit = self.user_invest.entry(*user).or_default();
+        let (to_amount, status) = match received {
+            Some(to_amount) if to_amount >= min_to_amount => {
+                if let Some(deposit) = token_deposit.get_mut(&from_token) {
+                    deposit.cost = reduced_cost(deposit, from_amount);
                 }
+                let to_deposit = token_deposit.entry(to_token.clone()).or_default();
+                to_deposit.amount += to_amount;
+                if to_token != USDT_NAME {
+                    to_deposit.cost += paid;
+                }
+                debug!(
+                    "{} Spend {} {}, buy {} {}",
+                    who, from_amount, from_token, to_amount, to_token
+                );
+                (to_amount, TradeStatus::Filled)
             }
-            debug!("{} total asset {}", who, total_asset);
+            _ => {
+                debug!(
+                    "{} failed to swap {} {} for at least {} {}",
+                    who, from_amount, from_token, min_to_amount, to_token
+                );
+                token_deposit.entry(from_token.clone()).or_default().amount += from_amount;
+                (0, TradeStatus::Failed)
+            }
+        };
+        let _ = msg::send(
+            *user,
+            TradeMsg {
+                time: exec::block_timestamp(),
+                from_token,
+                from_amount,
+                to_token,
+                to_amount,
+                min_to_amount,
+                status,
+            }
+            .encode(),
+            0,
+        );
+        (status == TradeStatus::Filled).then_some(to_amount)
+    }
+
+    fn set_strategy(&mut self, config: StrategyConfig) -> Result<(), OcqError> {
+        let source = msg::source();
+        let strategy = UserStrategy::new(config).ok_or(OcqError::InvalidParameters)?;
+        debug!("{:?} switch to strategy {:?}", source, strategy);
+        self.strategies.insert(source, strategy);
+        Ok(())
+    }
+
+    fn set_dca_plan(&mut self, dca_plan: DcaPlan) -> Result<(), OcqError> {
+        if !plan::is_valid(&dca_plan) {
+            return Err(OcqError::InvalidParameters);
+        }
+        self.dca_plans.insert(msg::source(), dca_plan);
+        Ok(())
+    }
+
+    fn set_dip_rule(&mut self, rule: Option<DipRule>) -> Result<(), OcqError> {
+        let source = msg::source();
+        match rule {
+            Some(rule) if !dip::is_valid(&rule) => return Err(OcqError::InvalidParameters),
+            Some(rule) => {
+                self.dip_rules.insert(source, rule);
+            }
+            None => {
+                self.dip_rules.remove(&source);
+            }
+        }
+        Ok(())
+    }
+
+    // a tradable token, the quote token is not one
+    fn check_tradable(&self, token: &str) -> Result<(), OcqError> {
+        if token == USDT_NAME || !self.token_info.contains_key(token) {
+            return Err(OcqError::UnknownToken(token.to_string()));
+        }
+        Ok(())
+    }
+
+    fn schedule_sell(&mut self, schedule: SellSchedule) -> Result<(), OcqError> {
+        self.check_tradable(&schedule.token)?;
+        if schedule.ratio == 0 || schedule.ratio as u128 > RATION_MULTIPLES || schedule.target == 0
+        {
+            return Err(OcqError::InvalidParameters);
+        }
+        let schedules = self.sell_schedules.entry(msg::source()).or_default();
+        schedules.retain(|s| s.token != schedule.token);
+        schedules.push(schedule);
+        Ok(())
+    }
+
+    fn place_order(
+        &mut self,
+        token: String,
+        kind: OrderKind,
+        amount: u128,
+    ) -> Result<OcqEvent, OcqError> {
+        let source = msg::source();
+        self.check_tradable(&token)?;
+        let valid_kind = match kind {
+            OrderKind::StopLoss { price } | OrderKind::TakeProfit { price } => price > 0,
+            OrderKind::TrailingStop { distance } => {
+                distance > 0 && (distance as u128) < RATION_MULTIPLES
+            }
+        };
+        if !valid_kind {
+            return Err(OcqError::InvalidParameters);
+        }
+        self.order_id += 1;
+        let id = self.order_id;
+        self.orders
+            .entry(source)
+            .or_default()
+            .push(ConditionalOrder {
+                id,
+                token,
+                kind,
+                amount,
+                peak: 0,
+            });
+        Ok(OcqEvent::OrderPlaced { id })
+    }
+
+    fn cancel_order(&mut self, id: u64) -> Result<(), OcqError> {
+        let source = msg::source();
+        if !self.has_order(&source, id) {
+            return Err(OcqError::UnknownOrder(id));
+        }
+        self.remove_order(&source, id);
+        Ok(())
+    }
+
+    fn cancel_sell(&mut self, token: String) -> Result<(), OcqError> {
+        let source = msg::source();
+        let schedules = self
+            .sell_schedules
+            .get_mut(&source)
+            .filter(|schedules| schedules.iter().any(|s| s.token == token))
+            .ok_or_else(|| OcqError::UnknownSchedule(token.clone()))?;
+        schedules.retain(|s| s.token != token);
+        if schedules.is_empty() {
+            self.sell_schedules.remove(&source);
+        }
+        Ok(())
+    }
+
+    // `None` to follow the global max slippage
+    fn set_slippage(&mut self, bps: Option<u32>) -> Result<(), OcqError> {
+        let source = msg::source();
+        match bps {
+            Some(bps) if bps as u128 > BPS_MULTIPLES => {
+                debug!("slippage {bps} is more than {BPS_MULTIPLES} bps");
+                return Err(OcqError::InvalidParameters);
+            }
+            Some(bps) => {
+                self.user_slippage.insert(source, bps);
+            }
+            None => {
+                self.user_slippage.remove(&source);
+            }
+        }
+        Ok(())
+    }
+
+    fn log_asset(&self, user: &ActorId, who: &str, prices: &HashMap<String, u64>) {
+        let Some(token_deposit) = self.user_invest.get(user) else {
+            return;
+        };
+        let mut total_asset = 0u128;
+        for (k, token) in token_deposit {
+            debug!("{} {} {}", who, k, token.amount);
+            total_asset += self
+                .quote(k, token.amount, USDT_NAME, prices)
+                .unwrap_or_default();
+        }
+        debug!("{} total asset {}", who, total_asset);
+    }
+
+    // current value of the parameter `param` sets
+    fn config_param(&self, param: &ConfigParam) -> ConfigParam {
+        match param {
+            ConfigParam::InvestRation(_) => ConfigParam::InvestRation(self.r_invest_ration),
+            ConfigParam::BlockStep(_) => ConfigParam::BlockStep(self.block_step),
+            ConfigParam::PriceSource(_) => ConfigParam::PriceSource(self.price_source.clone()),
+            ConfigParam::TwapWindow(_) => ConfigParam::TwapWindow(self.twap_window),
+            ConfigParam::Market(_) => ConfigParam::Market(self.market.config()),
+            ConfigParam::Dex(_) => ConfigParam::Dex(self.dex),
+            ConfigParam::MaxSlippage(_) => ConfigParam::MaxSlippage(self.max_slippage),
+        }
+    }
+
+    fn update_config(&mut self, param: ConfigParam) -> Result<ConfigChange, OcqError> {
+        self.only(&[Role::Operator])?;
+        let valid = match &param {
+            ConfigParam::InvestRation(ration) => *ration as u128 <= RATION_MULTIPLES,
+            ConfigParam::BlockStep(step) => *step > 0,
+            ConfigParam::Market(market) => market.correlation as u128 <= RATION_MULTIPLES,
+            ConfigParam::MaxSlippage(bps) => *bps as u128 <= BPS_MULTIPLES,
+            ConfigParam::PriceSource(_) | ConfigParam::TwapWindow(_) | ConfigParam::Dex(_) => true,
+        };
+        if !valid {
+            debug!("invalid config {:?}", param);
+            return Err(OcqError::InvalidParameters);
+        }
+        let previous = self.config_param(&param);
+        match param.clone() {
+            ConfigParam::InvestRation(ration) => self.r_invest_ration = ration,
+            ConfigParam::BlockStep(step) => self.block_step = step,
+            ConfigParam::PriceSource(price_source) => self.price_source = price_source,
+            ConfigParam::TwapWindow(window) => self.twap_window = window,
+            ConfigParam::Market(market) => self.market = MarketModel::from(market),
+            ConfigParam::Dex(dex) => self.dex = dex,
+            ConfigParam::MaxSlippage(bps) => self.max_slippage = bps,
+        }
+        let change = ConfigChange {
+            block: exec::block_height(),
+            timestamp: exec::block_timestamp(),
+            previous,
+            current: param,
+        };
+        debug!("config changed {:?}", change);
+        if self.config_log.len() >= MAX_CONFIG_LOG {
+            self.config_log.remove(0);
+        }
+        self.config_log.push(change.clone());
+        Ok(change)
+    }
+
+    fn register_token(&mut self, config: TokenConfig) -> Result<(), OcqError> {
+        self.only(&[Role::Operator])?;
+        if self.token_info.contains_key(&config.name) {
+            return Err(OcqError::TokenAlreadyRegistered(config.name));
+        }
+        if !valid_decimals(config.decimals) {
+            return Err(OcqError::InvalidParameters);
+        }
+        debug!("register token {}", config.name);
+        self.token_info
+            .insert(config.name.clone(), TokenInfo::from(config));
+        Ok(())
+    }
+
+    fn update_token(&mut self, config: TokenConfig) -> Result<(), OcqError> {
+        self.only(&[Role::Operator])?;
+        if !valid_decimals(config.decimals) {
+            return Err(OcqError::InvalidParameters);
+        }
+        let Some(info) = self.token_info.get(&config.name) else {
+            return Err(OcqError::UnknownToken(config.name));
+        };
+        // held balances are counted in these decimals and withdrawn from this program
+        if (info.program_id != config.program_id || info.decimals != config.decimals)
+            && self.is_held(&config.name)
+        {
+            return Err(OcqError::TokenInUse(config.name));
+        }
+        debug!("update token {}", config.name);
+        self.token_info
+            .insert(config.name.clone(), TokenInfo::from(config));
+        Ok(())
+    }
+
+    fn delist_token(&mut self, token: String) -> Result<(), OcqError> {
+        self.only(&[Role::Operator])?;
+        if !self.token_info.contains_key(&token) {
+            return Err(OcqError::UnknownToken(token));
+        }
+        // users must still be able to withdraw what they hold
+        if self.is_held(&token) {
+            debug!("token {token} is still held by users, disable it instead");
+            return Err(OcqError::TokenInUse(token));
         }
+        debug!("delist token {token}");
+        self.token_info.remove(&token);
+        self.fed_prices.remove(&token);
+        Ok(())
+    }
+
+    fn is_held(&self, token: &str) -> bool {
+        self.user_invest
+            .values()
+            .any(|m| m.get(token).map_or(false, |d| d.amount > 0))
     }
 
     fn check_reserve(&self, user: &ActorId) {
@@ -183,19 +1275,23 @@ impl OnchainQuant {
         }
     }
 
-    fn action(&mut self) {
+    async fn action(&mut self) -> Result<(), OcqError> {
         let block = exec::block_height();
+        if self.block_next == 0 {
+            return Err(OcqError::NotRunning);
+        }
         if self.block_next != block {
             debug!("scheduled in {0} instead of {block}", self.block_next);
-            return;
+            return Err(OcqError::NotScheduled);
         }
-        debug!("run action {} in block {}", self.action_id, block);
-        self.quant();
-        self.check_reserve(&self.owner);
-        let reservation = self
-            .reservations
-            .get(&self.owner)
-            .expect("can't find reservation");
+        // schedule the next round first, replies awaited by quant may come in later blocks
+        self.check_reserve(&self.runner);
+        let Some(reservation) = self.reservations.get(&self.runner) else {
+            self.block_next = 0;
+            return Err(OcqError::NoReservation);
+        };
+        let action_id = self.action_id;
+        debug!("run action {} in block {}", action_id, block);
         let _msg_id = msg::send_delayed_from_reservation(
             reservation.id(),
             exec::program_id(),
@@ -206,14 +1302,16 @@ impl OnchainQuant {
         .expect("msg_send");
         self.action_id += 1;
         self.block_next = block + self.block_step;
+        self.quant(action_id).await;
+        Ok(())
     }
 
-    fn reserve(&mut self, amount: u64, blocks: u32) -> OcqEvent {
+    fn reserve(&mut self, amount: u64, blocks: u32) -> Result<OcqEvent, OcqError> {
         let reservation = match Reservation::reserve(amount, blocks) {
             Ok(res) => res,
             Err(e) => {
                 debug!("reservation failed: {e}");
-                return OcqEvent::GasReserve { amount: 0, time: 0 };
+                return Err(OcqError::ReservationFailed);
             }
         };
 
@@ -223,26 +1321,284 @@ impl OnchainQuant {
             }
         }
         debug!("reserve {amount} gas for {blocks} blocks");
-        OcqEvent::GasReserve {
+        Ok(OcqEvent::GasReserve {
             amount,
             time: blocks,
-        }
+        })
     }
 
-    fn allocation_ration(&mut self, tokens: Vec<(String, u32)>) {
+    fn allocation_ration(&mut self, tokens: Vec<(String, u32)>) -> Result<(), OcqError> {
+        let names: BTreeSet<&String> = tokens.iter().map(|(token, _weight)| token).collect();
+        if tokens.is_empty() || names.len() != tokens.len() {
+            return Err(OcqError::InvalidWeights);
+        }
+        if let Some(token) = names.iter().find(|k| !self.token_info.contains_key(**k)) {
+            return Err(OcqError::UnknownToken(token.to_string()));
+        }
         let user_tokens = self.user_invest.entry(msg::source()).or_default();
         for (token, weight) in tokens {
             user_tokens.entry(token).or_default().weight = weight;
         }
+        Ok(())
     }
 
-    fn invest(&mut self, token: String, amount: u128) {
+    // deposits that can still be transferred back to their users, sorted
+    fn open_deposits(&self) -> Vec<(ActorId, String, u128)> {
+        self.deposits(true)
+    }
+
+    // deposits of tokens without an ft program, only kept in the books
+    // and converted to the quote token to be returned
+    fn book_deposits(&self) -> Vec<(ActorId, String, u128)> {
+        self.deposits(false)
+    }
+
+    fn deposits(&self, backed: bool) -> Vec<(ActorId, String, u128)> {
+        let mut deposits: Vec<(ActorId, String, u128)> = self
+            .user_invest
+            .iter()
+            .flat_map(|(user, deposits)| {
+                deposits
+                    .iter()
+                    .filter(|(token, deposit)| {
+                        deposit.amount > 0 && self.ft_program(token).is_some() == backed
+                    })
+                    .map(|(token, deposit)| (*user, token.clone(), deposit.amount))
+            })
+            .collect();
+        deposits.sort();
+        deposits
+    }
+
+    fn shutdown_status(&self) -> ShutdownStatus {
+        let Some(since) = self.shutdown else {
+            return ShutdownStatus::Active;
+        };
+        let open_deposits = self.open_deposits().len() as u32;
+        let book_deposits = self.book_deposits().len() as u32;
+        if open_deposits == 0 && book_deposits == 0 && self.pending_transfers == 0 {
+            return ShutdownStatus::Settled { since };
+        }
+        ShutdownStatus::Closing {
+            since,
+            open_deposits,
+            book_deposits,
+            pending_transfers: self.pending_transfers,
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<ShutdownStatus, OcqError> {
+        self.only(&[Role::Admin])?;
+        if self.shutdown.is_some() {
+            return Err(OcqError::ShuttingDown);
+        }
+        // the round already scheduled finds the program stopped
+        self.block_next = 0;
+        self.shutdown = Some(exec::block_height());
+        debug!("shutdown started");
+        Ok(self.shutdown_status())
+    }
+
+    async fn return_funds(&mut self) -> Result<OcqEvent, OcqError> {
+        self.only(&[Role::Admin])?;
+        if self.shutdown.is_none() {
+            return Err(OcqError::ShutdownNotStarted);
+        }
+        self.convert_book_deposits();
+        // each batch goes on after the last deposit tried, failed returns wait for the next pass
+        let mut deposits = self.open_deposits();
+        let start = self
+            .return_cursor
+            .as_ref()
+            .map_or(0, |(cursor_user, cursor_token)| {
+                deposits
+                    .iter()
+                    .position(|(user, token, _)| (user, token) > (cursor_user, cursor_token))
+                    .unwrap_or(0)
+            });
+        deposits.rotate_left(start);
+        let mut returned = 0;
+        for (user, token, amount) in deposits.into_iter().take(MAX_RETURNS) {
+            self.return_cursor = Some((user, token.clone()));
+            let Some(program_id) = self.ft_program(&token) else {
+                continue;
+            };
+            // debit first like a withdrawal, the user may withdraw while the transfer is pending
+            match self
+                .user_invest
+                .get_mut(&user)
+                .and_then(|m| m.get_mut(&token))
+            {
+                Some(deposit) if deposit.amount >= amount => deposit.amount -= amount,
+                _ => continue,
+            }
+            if self
+                .transfer(&program_id, &exec::program_id(), &user, amount)
+                .await
+                .is_err()
+            {
+                self.user_invest
+                    .entry(user)
+                    .or_default()
+                    .entry(token.clone())
+                    .or_default()
+                    .amount += amount;
+                debug!("return {amount} {token} to {:?} failed", user);
+                continue;
+            }
+            if let Some(deposit) = self
+                .user_invest
+                .get_mut(&user)
+                .and_then(|m| m.get_mut(&token))
+            {
+                deposit.cost = reduced_cost(deposit, amount);
+            }
+            debug!("return {amount} {token} to {:?}", user);
+            returned += 1;
+        }
+        Ok(OcqEvent::FundsReturned {
+            returned,
+            open_deposits: self.open_deposits().len() as u32,
+        })
+    }
+
+    // the value left goes to the admin terminating the program
+    fn terminate(&self) -> Result<(), OcqError> {
+        self.only(&[Role::Admin])?;
+        match self.shutdown_status() {
+            ShutdownStatus::Active => Err(OcqError::ShutdownNotStarted),
+            ShutdownStatus::Closing { .. } => Err(OcqError::FundsNotSettled),
+            ShutdownStatus::Settled { .. } => exec::exit(msg::source()),
+        }
+    }
+
+    // the quote token paid for the book deposits is in this program, they are valued
+    // at the last accepted prices, at what was paid for them without one
+    fn convert_book_deposits(&mut self) {
+        let prices: HashMap<String, u64> = self
+            .last_prices
+            .iter()
+            .map(|(token, price)| (token.clone(), price.price))
+            .collect();
+        for (user, token, amount) in self.book_deposits() {
+            let value = self.quote(&token, amount, USDT_NAME, &prices);
+            let deposits = self.user_invest.entry(user).or_default();
+            let Some(deposit) = deposits.get_mut(&token) else {
+                continue;
+            };
+            let value = value.unwrap_or(deposit.cost);
+            deposit.amount = 0;
+            deposit.cost = 0;
+            deposits.entry(USDT_NAME.to_string()).or_default().amount += value;
+            debug!(
+                "convert {amount} {token} of {:?} to {value} {USDT_NAME}",
+                user
+            );
+        }
+    }
+
+    fn ft_program(&self, token: &str) -> Option<ActorId> {
+        self.token_info
+            .get(token)
+            .map(|info| info.program_id)
+            .filter(|program_id| *program_id != ActorId::zero())
+    }
+
+    fn next_transaction_id(&mut self) -> u64 {
+        self.transaction_id += 1;
+        self.transaction_id
+    }
+
+    // counted as pending until the ft program replies
+    async fn transfer(
+        &mut self,
+        token: &ActorId,
+        sender: &ActorId,
+        recipient: &ActorId,
+        amount: u128,
+    ) -> Result<(), ()> {
+        let transaction_id = self.next_transaction_id();
+        self.pending_transfers += 1;
+        let result = ft::transfer(token, transaction_id, sender, recipient, amount).await;
+        self.pending_transfers -= 1;
+        result
+    }
+
+    // the user must approve this program on the ft program before investing
+    async fn invest(&mut self, token: String, amount: u128) -> Result<OcqEvent, OcqError> {
+        let source = msg::source();
+        if self.shutdown.is_some() {
+            return Err(OcqError::ShuttingDown);
+        }
+        match self.token_info.get(&token) {
+            None => return Err(OcqError::UnknownToken(token)),
+            Some(info) if !info.enabled => return Err(OcqError::TokenDisabled(token)),
+            Some(_) => {}
+        }
+        let Some(program_id) = self.ft_program(&token) else {
+            return Err(OcqError::NoFtProgram(token));
+        };
+        if self
+            .transfer(&program_id, &source, &exec::program_id(), amount)
+            .await
+            .is_err()
+        {
+            debug!("invest {amount} {token} for {:?} failed", source);
+            return Err(OcqError::TransferFailed);
+        }
         self.user_invest
-            .entry(msg::source())
+            .entry(source)
             .or_default()
-            .entry(token)
+            .entry(token.clone())
             .or_default()
             .amount += amount;
+        debug!("invest {amount} {token} for {:?}", source);
+        Ok(OcqEvent::Invest { token, amount })
+    }
+
+    async fn withdraw(&mut self, token: String, amount: u128) -> Result<OcqEvent, OcqError> {
+        let source = msg::source();
+        if !self.token_info.contains_key(&token) {
+            return Err(OcqError::UnknownToken(token));
+        }
+        let Some(program_id) = self.ft_program(&token) else {
+            return Err(OcqError::NoFtProgram(token));
+        };
+        match self
+            .user_invest
+            .get_mut(&source)
+            .and_then(|m| m.get_mut(&token))
+        {
+            Some(deposit) if deposit.amount >= amount => deposit.amount -= amount,
+            _ => {
+                debug!("{:?} has not enough {token} to withdraw {amount}", source);
+                return Err(OcqError::InsufficientBalance);
+            }
+        }
+        if self
+            .transfer(&program_id, &exec::program_id(), &source, amount)
+            .await
+            .is_err()
+        {
+            // give the debited amount back, the tokens never left the contract
+            self.user_invest
+                .entry(source)
+                .or_default()
+                .entry(token.clone())
+                .or_default()
+                .amount += amount;
+            debug!("withdraw {amount} {token} for {:?} failed", source);
+            return Err(OcqError::TransferFailed);
+        }
+        if let Some(deposit) = self
+            .user_invest
+            .get_mut(&source)
+            .and_then(|m| m.get_mut(&token))
+        {
+            deposit.cost = reduced_cost(deposit, amount);
+        }
+        debug!("withdraw {amount} {token} for {:?}", source);
+        Ok(OcqEvent::WithDraw { token, amount })
     }
 
     fn asset_of(&self) -> Vec<(String, u128)> {
@@ -253,139 +1609,160 @@ impl OnchainQuant {
     }
 }
 
-#[no_mangle]
-extern "C" fn handle() {
+#[gstd::async_main]
+async fn main() {
     let action: OcqAction = msg::load().expect("can not decode a handle action!");
     let quant: &mut OnchainQuant = unsafe { ONCHAIN_QUANT.get_or_insert(Default::default()) };
-    let rply = match action {
-        OcqAction::Start => {
-            quant.start();
-            OcqEvent::Success
-        }
-        OcqAction::Stop => {
-            quant.stop();
-            OcqEvent::Success
-        }
-        OcqAction::Act => {
-            quant.action();
-            OcqEvent::Success
-        }
+    let rply: Result<OcqEvent, OcqError> = match action {
+        OcqAction::Start => quant.start().await.map(|()| OcqEvent::Success),
+        OcqAction::Stop => quant.stop().map(|()| OcqEvent::Success),
+        OcqAction::Act => quant.act().await.map(|()| OcqEvent::Success),
         OcqAction::GasReserve { amount, blocks } => quant.reserve(amount, blocks),
         OcqAction::GasReserveDefault => quant.reserve(RESERVATION_AMOUNT, RESERVATION_TIME),
-        OcqAction::Terminate => {
-            exec::exit(quant.owner);
+        OcqAction::Terminate => quant.terminate().map(|()| OcqEvent::Success),
+        OcqAction::Shutdown => quant.shutdown().map(OcqEvent::Shutdown),
+        OcqAction::ReturnFunds => quant.return_funds().await,
+        OcqAction::GrantRole { role, account } => {
+            quant.grant_role(role, account).map(|()| OcqEvent::Success)
+        }
+        OcqAction::RevokeRole { role, account } => {
+            quant.revoke_role(role, account).map(|()| OcqEvent::Success)
+        }
+        OcqAction::FeedPrice { token, price } => {
+            quant.feed_price(token, price).map(|()| OcqEvent::Success)
         }
+        OcqAction::ProposeOwner { owner, blocks } => quant
+            .propose_owner(owner, blocks)
+            .map(OcqEvent::OwnershipProposed),
+        OcqAction::AcceptOwnership => quant.accept_ownership(),
+        OcqAction::RenounceOwnership => quant.renounce_ownership(),
         OcqAction::AssetAllocationRatio(tokens) => {
-            if !tokens.is_empty() {
-                quant.allocation_ration(tokens);
-            }
-            OcqEvent::Success
+            quant.allocation_ration(tokens).map(|()| OcqEvent::Success)
         }
-        OcqAction::Invest { token, amount } => {
-            // should query ft contract for the amount of tokens
-            quant.invest(token, amount);
-            OcqEvent::Success
+        OcqAction::Invest { token, amount } => quant.invest(token, amount).await,
+        OcqAction::WithDraw { token, amount } => quant.withdraw(token, amount).await,
+        OcqAction::Asset => Ok(OcqEvent::Asset(quant.asset_of())),
+        OcqAction::MaxSlippage(bps) => quant.set_slippage(bps).map(|()| OcqEvent::Success),
+        OcqAction::SetStrategy(config) => quant.set_strategy(config).map(|()| OcqEvent::Success),
+        OcqAction::SetDipRule(rule) => quant.set_dip_rule(rule).map(|()| OcqEvent::Success),
+        OcqAction::ScheduleSell {
+            token,
+            ratio,
+            floor,
+            target,
+        } => quant
+            .schedule_sell(SellSchedule {
+                token,
+                ratio,
+                floor,
+                target,
+                sold: 0,
+            })
+            .map(|()| OcqEvent::Success),
+        OcqAction::CancelSell(token) => quant.cancel_sell(token).map(|()| OcqEvent::Success),
+        OcqAction::PlaceOrder {
+            token,
+            kind,
+            amount,
+        } => quant.place_order(token, kind, amount),
+        OcqAction::CancelOrder(id) => quant.cancel_order(id).map(|()| OcqEvent::Success),
+        OcqAction::SetDcaPlan {
+            budget,
+            installments,
+            interval,
+            start_block,
+        } => quant
+            .set_dca_plan(DcaPlan {
+                budget,
+                installments,
+                paid: 0,
+                interval,
+                start_block,
+                next_block: start_block,
+            })
+            .map(|()| OcqEvent::Success),
+        OcqAction::DcaPlan => Ok(OcqEvent::DcaPlan(
+            quant.dca_plans.get(&msg::source()).cloned(),
+        )),
+        OcqAction::UpdateConfig(param) => quant.update_config(param).map(OcqEvent::ConfigChanged),
+        OcqAction::RegisterToken(config) => {
+            quant.register_token(config).map(|()| OcqEvent::Success)
         }
-        OcqAction::WithDraw {
-            token: _,
-            amount: _,
-        } => todo!(),
-        OcqAction::Asset => OcqEvent::Asset(quant.asset_of()),
+        OcqAction::UpdateToken(config) => quant.update_token(config).map(|()| OcqEvent::Success),
+        OcqAction::DelistToken(token) => quant.delist_token(token).map(|()| OcqEvent::Success),
+        OcqAction::ResetPrice(token) => quant.reset_price(token).map(|()| OcqEvent::Success),
     };
+    if let Err(e) = &rply {
+        debug!("action refused: {:?}", e);
+    }
     msg::reply(rply, 0).expect("error in sending reply");
 }
 
 #[no_mangle]
 extern "C" fn init() {
     let config: InitConfig = msg::load().expect("Unable to decode InitConfig");
-
-    let mut token_info = HashMap::new();
-    token_info.insert(
-        BTC_NAME.to_string(),
-        TokenInfo {
-            name: BTC_NAME.to_string(),
-            multiples: 1_0000_0000,
-        },
-    );
-    token_info.insert(
-        DOT_NAME.to_string(),
-        TokenInfo {
-            name: DOT_NAME.to_string(),
-            multiples: 10_000_000_000u64,
-        },
-    );
-    token_info.insert(
-        USDT_NAME.to_string(),
-        TokenInfo {
-            name: USDT_NAME.to_string(),
-            multiples: 1_000_000,
-        },
-    );
-    let mut token_deposit = HashMap::new();
-    token_deposit.insert(
-        BTC_NAME.to_string(),
-        TokenDeposit {
-            weight: 300,
-            amount: 0,
-        },
-    );
-    token_deposit.insert(
-        DOT_NAME.to_string(),
-        TokenDeposit {
-            weight: 200,
-            amount: 0,
-        },
+    assert!(
+        config
+            .tokens
+            .iter()
+            .all(|token| valid_decimals(token.decimals)),
+        "token decimals out of range"
     );
-    token_deposit.insert(
-        USDT_NAME.to_string(),
-        TokenDeposit {
-            weight: 500,
-            amount: 100_000 * 1_000_000u128,
-        },
-    );
-    let user_invest = dummy_user_invest(&token_deposit);
+
+    let token_info = config
+        .tokens
+        .into_iter()
+        .map(|config| (config.name.clone(), TokenInfo::from(config)))
+        .collect();
     let quant = OnchainQuant {
         r_invest_ration: config.r_invest_ration,
         reservations: HashMap::new(),
+        runner: msg::source(),
         block_step: config.block_step,
         block_next: 0,
         action_id: 0,
         owner: msg::source(),
         token_info,
-        user_invest,
+        // credited only by confirmed deposits
+        user_invest: HashMap::new(),
+        transaction_id: 0,
+        pending_transfers: 0,
+        price_source: config.price_source,
+        last_prices: HashMap::new(),
+        price_alerts: Vec::new(),
+        price_rounds: Vec::new(),
+        price_history: HashMap::new(),
+        twap_window: config.twap_window,
+        market: MarketModel::from(config.market),
+        dex: config.dex,
+        max_slippage: config.max_slippage.min(BPS_MULTIPLES as u32),
+        user_slippage: HashMap::new(),
+        strategies: HashMap::new(),
+        dip_rules: HashMap::new(),
+        sell_schedules: HashMap::new(),
+        orders: HashMap::new(),
+        order_id: 0,
+        dca_plans: HashMap::new(),
+        config_log: Vec::new(),
+        roles: [Role::Admin, Role::Operator, Role::Pauser, Role::PriceFeeder]
+            .into_iter()
+            .map(|role| (role, BTreeSet::from([msg::source()])))
+            .collect(),
+        fed_prices: HashMap::new(),
+        pending_owner: None,
+        shutdown: None,
+        return_cursor: None,
     };
     unsafe { ONCHAIN_QUANT = Some(quant) };
-    price::init();
 }
 
-fn actor_id_from_str(other: &str) -> ActorId {
-    let id = other.strip_prefix("0x").unwrap_or(other);
-
-    let mut bytes = [0u8; 32];
-
-    if hex::decode_to_slice(id, &mut bytes).is_err() {
-        panic!("Invalid identifier: {:?}", other)
+// cost of `deposit` left after `amount` of it is gone, `deposit.amount` is what remains
+fn reduced_cost(deposit: &TokenDeposit, amount: u128) -> u128 {
+    let held = deposit.amount + amount;
+    if held == 0 {
+        return 0;
     }
-
-    ActorId::from(bytes)
-}
-
-fn dummy_user_invest(
-    prototype: &HashMap<String, TokenDeposit>,
-) -> HashMap<ActorId, HashMap<String, TokenDeposit>> {
-    let actors = [
-        "0x54d13cda7abe4aab7adbe1b7d2da8662934f33c628d7737d2be33e95075fc77e",
-        "0x4ccf35ad0f22d5a83a6a0608bcbbce9992974293ac492858a2370a93af9ebd45",
-        "0x8472f7754a62850263727957b7acf7d077961a9e94816fce2780c72d5a2a5717",
-        "0x327caff531d22348427ca6d7a051cecc6d621a72c8d9db3be8dd544fa78a263c",
-    ];
-    let mut dest = HashMap::new();
-    for actor in actors {
-        let actor = actor_id_from_str(actor);
-        dest.insert(actor, prototype.clone());
-    }
-    dest.insert(exec::program_id(), prototype.clone());
-    dest
+    deposit.cost - deposit.cost * amount / held
 }
 
 #[no_mangle]
@@ -401,11 +1778,80 @@ fn reply(payload: impl Encode) -> GstdResult<MessageId> {
 fn common_state() -> IOOnchainQuant {
     let state = static_mut_state();
     let r_invest_ration = state.r_invest_ration;
+    let now = exec::block_timestamp();
+    let twap_window = if state.twap_window == 0 {
+        STATE_TWAP_WINDOW
+    } else {
+        state.twap_window
+    };
     IOOnchainQuant {
         r_invest_ration,
         block_step: state.block_step,
         block_next: state.block_next,
         action_id: state.action_id,
+        tokens: state.token_info.values().map(TokenConfig::from).collect(),
+        price_source: state.price_source.clone(),
+        price_alerts: state.price_alerts.clone(),
+        price_rounds: state.price_rounds.clone(),
+        price_history: state
+            .price_history
+            .iter()
+            .map(|(token, history)| (token.clone(), history.iter().cloned().collect()))
+            .collect(),
+        twap_window: state.twap_window,
+        twap: state
+            .price_history
+            .iter()
+            .filter_map(|(token, history)| {
+                history
+                    .twap(twap_window, now)
+                    .map(|twap| (token.clone(), twap))
+            })
+            .collect(),
+        market: state.market.config(),
+        dex: state.dex,
+        max_slippage: state.max_slippage,
+        strategies: state
+            .strategies
+            .iter()
+            .map(|(user, strategy)| (*user, strategy.config()))
+            .collect(),
+        grids: state
+            .strategies
+            .iter()
+            .filter_map(|(user, strategy)| strategy.grid_state().map(|grid| (*user, grid)))
+            .collect(),
+        dip_rules: state
+            .dip_rules
+            .iter()
+            .map(|(user, rule)| (*user, rule.clone()))
+            .collect(),
+        sell_schedules: state
+            .sell_schedules
+            .iter()
+            .flat_map(|(user, schedules)| {
+                schedules.iter().map(|schedule| (*user, schedule.clone()))
+            })
+            .collect(),
+        orders: state
+            .orders
+            .iter()
+            .flat_map(|(user, orders)| orders.iter().map(|order| (*user, order.clone())))
+            .collect(),
+        dca_plans: state
+            .dca_plans
+            .iter()
+            .map(|(user, dca_plan)| (*user, dca_plan.clone()))
+            .collect(),
+        config_log: state.config_log.clone(),
+        roles: state
+            .roles
+            .iter()
+            .map(|(role, holders)| (*role, holders.iter().copied().collect()))
+            .collect(),
+        owner: state.owner,
+        pending_owner: state.pending_owner.clone(),
+        shutdown: state.shutdown_status(),
     }
 }
 
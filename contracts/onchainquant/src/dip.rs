@@ -0,0 +1,28 @@
+use onchainquant_io::DipRule;
+
+use crate::contract::RATION_MULTIPLES;
+
+pub(crate) fn is_valid(rule: &DipRule) -> bool {
+    rule.max_budget as u128 <= RATION_MULTIPLES
+        && rule
+            .tiers
+            .iter()
+            .all(|(drop, _multiplier)| *drop as u128 <= RATION_MULTIPLES)
+}
+
+// how far `price` is below `reference`, in 0.000001
+pub(crate) fn drop(price: u64, reference: u64) -> u64 {
+    if reference == 0 || price >= reference {
+        return 0;
+    }
+    ((reference - price) as u128 * RATION_MULTIPLES / reference as u128) as u64
+}
+
+// budget multiplier of the deepest tier `drop` reaches, in 0.000001
+pub(crate) fn multiplier(rule: &DipRule, drop: u64) -> u64 {
+    rule.tiers
+        .iter()
+        .filter(|(tier, _multiplier)| drop >= *tier)
+        .max_by_key(|(tier, _multiplier)| *tier)
+        .map_or(RATION_MULTIPLES as u64, |(_tier, multiplier)| *multiplier)
+}
@@ -0,0 +1,69 @@
+use ft_main_io::{FTokenAction, FTokenEvent, LogicAction};
+use gstd::{debug, msg, ActorId};
+
+// transfer `amount` of the sharded fungible token at `token` from `sender` to `recipient`,
+// transfers from another account need an approval for this program first
+pub(crate) async fn transfer(
+    token: &ActorId,
+    transaction_id: u64,
+    sender: &ActorId,
+    recipient: &ActorId,
+    amount: u128,
+) -> Result<(), ()> {
+    send(
+        token,
+        transaction_id,
+        LogicAction::Transfer {
+            sender: *sender,
+            recipient: *recipient,
+            amount,
+        },
+    )
+    .await
+}
+
+// let `approved_account` transfer `amount` of the token at `token` from this program
+pub(crate) async fn approve(
+    token: &ActorId,
+    transaction_id: u64,
+    approved_account: &ActorId,
+    amount: u128,
+) -> Result<(), ()> {
+    send(
+        token,
+        transaction_id,
+        LogicAction::Approve {
+            approved_account: *approved_account,
+            amount,
+        },
+    )
+    .await
+}
+
+async fn send(token: &ActorId, transaction_id: u64, payload: LogicAction) -> Result<(), ()> {
+    let future = match msg::send_for_reply_as::<_, FTokenEvent>(
+        *token,
+        FTokenAction::Message {
+            transaction_id,
+            payload,
+        },
+        0,
+    ) {
+        Ok(future) => future,
+        Err(e) => {
+            debug!("send message to ft program failed {e}");
+            return Err(());
+        }
+    };
+    match future.await {
+        Ok(FTokenEvent::Ok) => Ok(()),
+        Ok(_) => {
+            debug!("ft message {transaction_id} rejected");
+            Err(())
+        }
+        Err(e) => {
+            debug!("ft message {transaction_id} failed {e}");
+            Err(())
+        }
+    }
+}
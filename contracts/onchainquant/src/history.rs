@@ -0,0 +1,64 @@
+use gstd::prelude::*;
+use onchainquant_io::PriceObservation;
+
+// observations kept for each token
+const PRICE_HISTORY_LEN: usize = 256;
+
+// ring buffer of the latest price observations of a token
+#[derive(Debug, Clone, Default)]
+pub struct PriceHistory {
+    observations: Vec<PriceObservation>,
+    // slot of the next observation once the buffer is full
+    next: usize,
+}
+
+impl PriceHistory {
+    pub fn push(&mut self, observation: PriceObservation) {
+        if self.observations.len() < PRICE_HISTORY_LEN {
+            self.observations.push(observation);
+        } else {
+            self.observations[self.next] = observation;
+        }
+        self.next = (self.next + 1) % PRICE_HISTORY_LEN;
+    }
+
+    // oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &PriceObservation> {
+        let (newer, older) = if self.observations.len() < PRICE_HISTORY_LEN {
+            (&self.observations[..], &self.observations[..0])
+        } else {
+            self.observations.split_at(self.next)
+        };
+        older.iter().chain(newer.iter())
+    }
+
+    pub fn last(&self) -> Option<&PriceObservation> {
+        self.iter().next_back()
+    }
+
+    // time weighted average price of the last `window` ms before `now`,
+    // each price holds until the next observation, the last one until `now`
+    pub fn twap(&self, window: u64, now: u64) -> Option<u64> {
+        let start = now.saturating_sub(window);
+        let mut weighted = 0u128;
+        let mut total = 0u128;
+        let mut until = now;
+        for observation in self.iter().rev() {
+            let from = observation.timestamp.max(start);
+            if until > from {
+                let span = (until - from) as u128;
+                weighted += observation.price as u128 * span;
+                total += span;
+            }
+            if observation.timestamp <= start {
+                break;
+            }
+            until = observation.timestamp;
+        }
+        if total == 0 {
+            // observed at `now` only
+            return self.last().map(|o| o.price);
+        }
+        Some((weighted / total) as u64)
+    }
+}
@@ -3,4 +3,10 @@
 #[cfg(not(feature = "binary-vendor"))]
 mod contract;
 
+mod dip;
+mod ft;
+mod history;
+mod plan;
 mod price;
+mod strategy;
+mod swap;
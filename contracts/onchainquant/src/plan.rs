@@ -0,0 +1,30 @@
+use onchainquant_io::{Budget, DcaPlan};
+
+use crate::contract::RATION_MULTIPLES;
+
+pub(crate) fn is_valid(plan: &DcaPlan) -> bool {
+    let budget_valid = match plan.budget {
+        Budget::Ratio { ratio, min, max } => ratio as u128 <= RATION_MULTIPLES && min <= max,
+        Budget::Fixed(amount) => amount > 0,
+    };
+    budget_valid && plan.installments != Some(0)
+}
+
+pub(crate) fn is_complete(plan: &DcaPlan) -> bool {
+    plan.installments
+        .map_or(false, |installments| plan.paid >= installments)
+}
+
+// quote token put to work in a round out of the `usdt` held, 0 once the plan is complete
+pub(crate) fn budget(plan: &DcaPlan, usdt: u128) -> u128 {
+    if is_complete(plan) {
+        return 0;
+    }
+    let budget = match plan.budget {
+        Budget::Ratio { ratio, min, max } => {
+            (usdt * ratio as u128 / RATION_MULTIPLES).clamp(min, max)
+        }
+        Budget::Fixed(amount) => amount,
+    };
+    budget.min(usdt)
+}
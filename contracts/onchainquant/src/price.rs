@@ -1,4 +1,5 @@
-use gstd::{debug, exec, HashMap, String, ToString};
+use gstd::{debug, exec, msg, prelude::*, ActorId};
+use onchainquant_io::{MarketModelConfig, OracleAction, OracleEvent, PriceSource, SimulatedToken};
 
 use rand::{Rng, SeedableRng};
 
@@ -6,45 +7,233 @@ use rand_xoshiro::Xoshiro128PlusPlus;
 
 use crate::contract::{BTC_NAME, DOT_NAME};
 
-static mut FUNGIBLE_TOKENS: Option<HashMap<String, u64>> = None;
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokenPrice {
+    // in quote token units
+    pub price: u64,
+    // block timestamp of the price update
+    pub updated_at: u64,
+}
+
+// drift, volatility and correlation are in 0.000001
+const UNIT: i128 = 1_000_000;
+// a step never moves a price below 1% of its last value
+const MIN_STEP_FACTOR: i128 = UNIT / 100;
+
+// geometric brownian motion of the simulated tokens, every token follows
+// a shared market shock and its own shock, weighted by the correlation
+#[derive(Debug, Clone, Default)]
+pub struct MarketModel {
+    tokens: BTreeMap<String, SimulatedToken>,
+    correlation: u64,
+}
 
-pub(crate) fn init() {
-    let mut tokens = HashMap::new();
-    tokens.insert(BTC_NAME.to_string(), 26_500u64 * 1_000_000u64);
-    tokens.insert(DOT_NAME.to_string(), 4_120_000u64);
-    unsafe {
-        FUNGIBLE_TOKENS = Some(tokens);
+impl From<MarketModelConfig> for MarketModel {
+    fn from(config: MarketModelConfig) -> Self {
+        if config.tokens.is_empty() {
+            return MarketModel::testnet();
+        }
+        MarketModel {
+            tokens: config
+                .tokens
+                .into_iter()
+                .map(|token| (token.name.clone(), token))
+                .collect(),
+            correlation: config.correlation.min(UNIT as u64),
+        }
     }
 }
 
-pub fn get_price() -> HashMap<String, u64> {
-    let base_price = unsafe { FUNGIBLE_TOKENS.get_or_insert(Default::default()) };
+impl MarketModel {
+    // the testnet tokens, 2% volatility per round and half correlated
+    fn testnet() -> Self {
+        let token = |name: &str, price: u64| SimulatedToken {
+            name: name.to_string(),
+            price,
+            drift: 0,
+            volatility: 20_000,
+        };
+        let tokens = [
+            token(BTC_NAME, 26_500u64 * 1_000_000u64),
+            token(DOT_NAME, 4_120_000u64),
+        ];
+        MarketModel {
+            tokens: tokens
+                .into_iter()
+                .map(|token| (token.name.clone(), token))
+                .collect(),
+            correlation: 500_000,
+        }
+    }
+
+    pub fn config(&self) -> MarketModelConfig {
+        MarketModelConfig {
+            tokens: self.tokens.values().cloned().collect(),
+            correlation: self.correlation,
+        }
+    }
+
+    // move every price one step, seeded by the program and the block timestamp
+    // so that runs are reproducible
+    fn step(&mut self) {
+        let exec_program = exec::program_id();
+        let block_timestamp = exec::block_timestamp();
+        let seed = sp_core_hashing::blake2_128(
+            &[exec_program.as_ref(), &block_timestamp.to_le_bytes()].concat(),
+        );
+        let mut generator = Xoshiro128PlusPlus::from_seed(seed);
+
+        let market_shock = normal(&mut generator);
+        let market_weight = isqrt(self.correlation as i128 * UNIT);
+        let own_weight = isqrt((UNIT - self.correlation as i128) * UNIT);
+        for (k, token) in self.tokens.iter_mut() {
+            let shock = (market_weight * market_shock + own_weight * normal(&mut generator)) / UNIT;
+            let ratio = token.drift as i128 + token.volatility as i128 * shock / UNIT;
+            let factor = (UNIT + ratio).max(MIN_STEP_FACTOR);
+            token.price = ((token.price as i128 * factor / UNIT) as u64).max(1);
+            debug!(
+                "get {k} price ratio {0}.{1:0>4}%, final price {2}",
+                ratio / 10_000,
+                ratio.abs() % 10_000,
+                token.price,
+            );
+        }
+    }
+}
 
-    let exec_program = exec::program_id();
-    let block_timestamp = exec::block_timestamp();
-    let seed = sp_core_hashing::blake2_128(
-        &[
-            exec_program.as_ref(),
-            &block_timestamp.to_le_bytes(),
-            // token.as_bytes(),
-        ]
-        .concat(),
-    );
+// standard normal sample in 0.000001, the sum of 12 uniform samples
+fn normal(generator: &mut Xoshiro128PlusPlus) -> i128 {
+    (0..12).map(|_| generator.gen_range(0..UNIT)).sum::<i128>() - 6 * UNIT
+}
 
-    let mut generator = Xoshiro128PlusPlus::from_seed(seed);
-    let range = -99..100;
+fn isqrt(n: i128) -> i128 {
+    if n < 2 {
+        return n.max(0);
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
 
+// prices of `tokens` from `source`, tokens without a price are left out
+pub(crate) async fn get_price(
+    source: &PriceSource,
+    tokens: &[String],
+    market: &mut MarketModel,
+    fed: &HashMap<String, (ActorId, TokenPrice)>,
+) -> HashMap<String, TokenPrice> {
+    match source {
+        PriceSource::Simulated => simulated_price(tokens, market),
+        PriceSource::Oracle(oracle) => oracle_price(oracle, tokens).await,
+        PriceSource::Feed => tokens
+            .iter()
+            .filter_map(|token| fed.get(token).map(|(_, price)| (token.clone(), *price)))
+            .collect(),
+    }
+}
+
+async fn oracle_price(oracle: &ActorId, tokens: &[String]) -> HashMap<String, TokenPrice> {
     let mut dest = HashMap::new();
-    for (k, v) in base_price.iter() {
-        let ratio: i32 = generator.gen_range(range.clone());
-        let dest_price = v * (1000 + ratio) as u64 / 1000;
-        let n = ratio / 10;
-        let p = ratio.abs() % 10;
-        debug!(
-            "get {k} price ratio {0}.{1:0>1}%, final price {dest_price}",
-            n, p,
-        );
-        dest.insert(k.to_string(), dest_price);
+    for token in tokens {
+        if let Some(price) = query_oracle(oracle, token).await {
+            dest.insert(token.to_string(), price);
+        }
     }
     dest
 }
+
+async fn query_oracle(oracle: &ActorId, token: &str) -> Option<TokenPrice> {
+    let reply = match msg::send_for_reply_as::<_, OracleEvent>(
+        *oracle,
+        OracleAction::GetPrice(token.to_string()),
+        0,
+    ) {
+        Ok(future) => future.await,
+        Err(e) => {
+            debug!("send price query of {token} failed {e}");
+            return None;
+        }
+    };
+    match reply {
+        // nothing is worth nothing, a zero price would divide the conversions by zero
+        Ok(OracleEvent::Price { price: 0, .. }) => {
+            debug!("oracle answered a zero price of {token}");
+            None
+        }
+        Ok(OracleEvent::Price {
+            price, updated_at, ..
+        }) => {
+            debug!("get {token} price {price} from oracle, updated at {updated_at}");
+            Some(TokenPrice { price, updated_at })
+        }
+        Ok(OracleEvent::NoPrice(_)) => {
+            debug!("oracle has no price of {token}");
+            None
+        }
+        Err(e) => {
+            debug!("query {token} price failed {e}");
+            None
+        }
+    }
+}
+
+// answers of `oracles` for `token`, answers older than `max_age` ms are dropped unless it is 0
+pub(crate) async fn collect_prices(
+    oracles: &[ActorId],
+    token: &str,
+    max_age: u64,
+) -> Vec<(ActorId, TokenPrice)> {
+    let now = exec::block_timestamp();
+    let mut dest = Vec::new();
+    for oracle in oracles {
+        match query_oracle(oracle, token).await {
+            Some(price) if max_age > 0 && now.saturating_sub(price.updated_at) > max_age => {
+                debug!("drop stale {token} price from {:?}", oracle);
+            }
+            Some(price) => dest.push((*oracle, price)),
+            None => {}
+        }
+    }
+    dest
+}
+
+// median of the prices, the oldest update time among them is kept
+pub(crate) fn median(quotes: &[(ActorId, TokenPrice)]) -> Option<TokenPrice> {
+    let mut prices: Vec<u64> = quotes.iter().map(|(_, p)| p.price).collect();
+    prices.sort_unstable();
+    let mid = prices.len() / 2;
+    let price = match prices.len() {
+        0 => return None,
+        n if n % 2 == 1 => prices[mid],
+        _ => ((prices[mid - 1] as u128 + prices[mid] as u128) / 2) as u64,
+    };
+    let updated_at = quotes
+        .iter()
+        .map(|(_, p)| p.updated_at)
+        .min()
+        .unwrap_or_default();
+    Some(TokenPrice { price, updated_at })
+}
+
+fn simulated_price(tokens: &[String], market: &mut MarketModel) -> HashMap<String, TokenPrice> {
+    market.step();
+    let updated_at = exec::block_timestamp();
+    market
+        .tokens
+        .iter()
+        .filter(|(k, _v)| tokens.contains(*k))
+        .map(|(k, v)| {
+            (
+                k.to_string(),
+                TokenPrice {
+                    price: v.price,
+                    updated_at,
+                },
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,17 @@
+use gstd::prelude::*;
+
+use super::{Strategy, StrategyContext, Trade};
+
+// dollar-cost averaging, the budget of each round buys the tokens by their weights
+#[derive(Debug, Clone, Default)]
+pub struct Dca;
+
+impl Strategy for Dca {
+    fn plan(&mut self, ctx: &StrategyContext) -> Vec<Trade> {
+        ctx.buy_by_weights(ctx.budget)
+    }
+
+    fn spends_budget(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,121 @@
+use gstd::prelude::*;
+use onchainquant_io::{GridState, StrategyConfig};
+
+use super::{Strategy, StrategyContext, Trade};
+use crate::contract::USDT_NAME;
+
+// bounds the trades of a round
+const MAX_GRID_LEVELS: u32 = 100;
+
+// grid trading, `capital` quote token buys `token` each time the price falls to a level,
+// what a level bought is sold when the price rises to the level above it
+#[derive(Debug, Clone)]
+pub struct Grid {
+    token: String,
+    lower: u64,
+    upper: u64,
+    capital: u128,
+    // (price, amount bought), 0 if the level is not filled
+    levels: Vec<(u64, u128)>,
+    last_price: Option<u64>,
+    profit: i128,
+}
+
+impl Grid {
+    pub fn new(token: String, lower: u64, upper: u64, levels: u32, capital: u128) -> Option<Self> {
+        if token == USDT_NAME
+            || lower >= upper
+            || !(2..=MAX_GRID_LEVELS).contains(&levels)
+            || capital == 0
+        {
+            return None;
+        }
+        let steps = levels as u128 - 1;
+        let levels = (0..levels as u128)
+            .map(|i| {
+                let price = lower as u128 + (upper - lower) as u128 * i / steps;
+                (price as u64, 0)
+            })
+            .collect();
+        Some(Grid {
+            token,
+            lower,
+            upper,
+            capital,
+            levels,
+            last_price: None,
+            profit: 0,
+        })
+    }
+
+    pub fn config(&self) -> StrategyConfig {
+        StrategyConfig::Grid {
+            token: self.token.clone(),
+            lower: self.lower,
+            upper: self.upper,
+            levels: self.levels.len() as u32,
+            capital: self.capital,
+        }
+    }
+
+    pub fn state(&self) -> GridState {
+        GridState {
+            levels: self.levels.clone(),
+            last_price: self.last_price.unwrap_or_default(),
+            profit: self.profit,
+        }
+    }
+}
+
+impl Strategy for Grid {
+    fn plan(&mut self, ctx: &StrategyContext) -> Vec<Trade> {
+        let Some(price) = ctx.prices.get(&self.token).copied() else {
+            return vec![];
+        };
+        // the first price only sets where the crossings are counted from
+        let Some(last) = self.last_price.replace(price) else {
+            return vec![];
+        };
+        let mut trades = vec![];
+        if price < last {
+            // the top level has no level above to sell at
+            let buy_levels = self.levels.len() - 1;
+            for (i, (level, amount)) in self.levels.iter().enumerate().take(buy_levels) {
+                if *amount == 0 && price <= *level && *level < last {
+                    trades.push(Trade {
+                        tag: Some(i),
+                        ..ctx.buy(&self.token, self.capital)
+                    });
+                }
+            }
+        } else {
+            for i in 1..self.levels.len() {
+                let (level, _) = self.levels[i];
+                let (_, amount) = self.levels[i - 1];
+                if amount > 0 && last < level && level <= price {
+                    trades.push(Trade {
+                        tag: Some(i - 1),
+                        ..ctx.sell(&self.token, amount)
+                    });
+                }
+            }
+        }
+        trades
+    }
+
+    // the trade is tagged with its level, a grid set up while it was pending may not have it
+    fn settle(&mut self, trade: &Trade, received: Option<u128>) {
+        let (Some(i), Some(received)) = (trade.tag, received) else {
+            return;
+        };
+        let Some((_, amount)) = self.levels.get_mut(i) else {
+            return;
+        };
+        if trade.to_token == self.token {
+            *amount = received;
+        } else {
+            *amount = 0;
+            self.profit += received as i128 - self.capital as i128;
+        }
+    }
+}
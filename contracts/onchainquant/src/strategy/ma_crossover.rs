@@ -0,0 +1,57 @@
+use gstd::prelude::*;
+
+use super::{Strategy, StrategyContext, Trade};
+use crate::contract::USDT_NAME;
+
+// trend following, a token is bought with its weighted share of the quote token when its
+// short moving average crosses above the long one, and sold when it crosses below
+#[derive(Debug, Clone)]
+pub struct MaCrossover {
+    // windows in ms
+    pub short: u64,
+    pub long: u64,
+    // token => whether the short average was above the long one in the last round
+    above: BTreeMap<String, bool>,
+}
+
+impl MaCrossover {
+    pub fn new(short: u64, long: u64) -> Option<Self> {
+        (short > 0 && short < long).then_some(MaCrossover {
+            short,
+            long,
+            above: BTreeMap::new(),
+        })
+    }
+}
+
+impl Strategy for MaCrossover {
+    fn plan(&mut self, ctx: &StrategyContext) -> Vec<Trade> {
+        let usdt = ctx.amount(USDT_NAME);
+        let weight_sum: u32 = ctx.weights().map(|(_k, weight)| weight).sum();
+        let mut trades = vec![];
+        for (k, weight) in ctx.weights() {
+            let (Some(short), Some(long)) = (
+                ctx.moving_average(k, self.short),
+                ctx.moving_average(k, self.long),
+            ) else {
+                continue;
+            };
+            let above = short > long;
+            // the first signal of a token is not a crossing
+            match self.above.insert(k.clone(), above) {
+                Some(false) if above => {
+                    let budget = (usdt * weight as u128)
+                        .checked_div(weight_sum as u128)
+                        .unwrap_or_default();
+                    trades.push(ctx.buy(k, budget));
+                }
+                Some(true) if !above => trades.push(ctx.sell(k, ctx.amount(k))),
+                _ => {}
+            }
+        }
+        trades
+            .into_iter()
+            .filter(|trade| trade.from_amount > 0)
+            .collect()
+    }
+}
@@ -0,0 +1,246 @@
+use gstd::prelude::*;
+use onchainquant_io::{GridState, StrategyConfig};
+
+use crate::{
+    contract::{TokenDeposit, TokenInfo, USDT_NAME},
+    history::PriceHistory,
+};
+
+mod dca;
+mod grid;
+mod ma_crossover;
+mod rebalance;
+mod value_averaging;
+
+pub use dca::Dca;
+pub use grid::Grid;
+pub use ma_crossover::MaCrossover;
+pub use rebalance::Rebalance;
+pub use value_averaging::ValueAveraging;
+
+// sell `from_amount` of `from_token` for `to_token`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trade {
+    pub from_token: String,
+    pub from_amount: u128,
+    pub to_token: String,
+    // what the planning strategy knows the trade by, handed back to `settle` with it
+    pub tag: Option<usize>,
+}
+
+// what a strategy sees of a user in a round
+pub struct StrategyContext<'a> {
+    // token => deposit of the user
+    pub portfolio: &'a HashMap<String, TokenDeposit>,
+    // prices of the tradable tokens in this round, the quote token is not in it
+    pub prices: &'a HashMap<String, u64>,
+    pub token_info: &'a HashMap<String, TokenInfo>,
+    pub history: &'a HashMap<String, PriceHistory>,
+    // block timestamp of the round
+    pub now: u64,
+    // quote token the user plans to put to work in this round
+    pub budget: u128,
+}
+
+impl<'a> StrategyContext<'a> {
+    pub fn amount(&self, token: &str) -> u128 {
+        self.portfolio.get(token).map_or(0, |d| d.amount)
+    }
+
+    // amount of `to` worth `amount` of `from`
+    pub fn convert(&self, from: &str, amount: u128, to: &str) -> Option<u128> {
+        convert(self.token_info, self.prices, from, amount, to)
+    }
+
+    // value of the token held, in quote token
+    pub fn value(&self, token: &str) -> u128 {
+        self.convert(token, self.amount(token), USDT_NAME)
+            .unwrap_or_default()
+    }
+
+    // value of the tradable tokens held, in quote token
+    pub fn tokens_value(&self) -> u128 {
+        self.prices.keys().map(|k| self.value(k)).sum()
+    }
+
+    // value of the quote token and the tradable tokens held
+    pub fn total_value(&self) -> u128 {
+        self.value(USDT_NAME) + self.tokens_value()
+    }
+
+    // time weighted average price of `token` over the last `window` ms
+    pub fn moving_average(&self, token: &str, window: u64) -> Option<u64> {
+        self.history
+            .get(token)
+            .and_then(|history| history.twap(window, self.now))
+    }
+
+    // tradable tokens of the user with their weights
+    pub fn weights(&self) -> impl Iterator<Item = (&'a String, u32)> + '_ {
+        self.portfolio
+            .iter()
+            .filter(move |(k, _v)| self.prices.contains_key(*k))
+            .map(|(k, v)| (k, v.weight))
+    }
+
+    pub fn buy(&self, token: &str, budget: u128) -> Trade {
+        Trade {
+            from_token: USDT_NAME.to_string(),
+            from_amount: budget,
+            to_token: token.to_string(),
+            tag: None,
+        }
+    }
+
+    // split `budget` of quote token among the tradable tokens by their weights
+    pub fn buy_by_weights(&self, budget: u128) -> Vec<Trade> {
+        let weight_sum: u32 = self.weights().map(|(_k, weight)| weight).sum();
+        if weight_sum == 0 {
+            return vec![];
+        }
+        self.weights()
+            .map(|(k, weight)| self.buy(k, budget * weight as u128 / weight_sum as u128))
+            .filter(|trade| trade.from_amount > 0)
+            .collect()
+    }
+
+    pub fn sell(&self, token: &str, amount: u128) -> Trade {
+        Trade {
+            from_token: token.to_string(),
+            from_amount: amount,
+            to_token: USDT_NAME.to_string(),
+            tag: None,
+        }
+    }
+}
+
+// amount of `to` worth `amount` of `from` at `prices`, both tokens are valued in quote token
+pub fn convert(
+    token_info: &HashMap<String, TokenInfo>,
+    prices: &HashMap<String, u64>,
+    from: &str,
+    amount: u128,
+    to: &str,
+) -> Option<u128> {
+    let multiples = |token: &str| token_info.get(token).map(|info| info.multiples as u128);
+    let price = |token: &str| prices.get(token).map(|price| *price as u128);
+    let value = if from == USDT_NAME {
+        amount
+    } else {
+        amount * price(from)? / multiples(from)?
+    };
+    if to == USDT_NAME {
+        Some(value)
+    } else {
+        Some(value * multiples(to)? / price(to)?)
+    }
+}
+
+pub trait Strategy {
+    // trades of the round, executed in order
+    fn plan(&mut self, ctx: &StrategyContext) -> Vec<Trade>;
+
+    // what a planned trade received, `None` if it failed, rounds may overlap
+    // so the trade carries what the strategy needs to know it by
+    fn settle(&mut self, _trade: &Trade, _received: Option<u128>) {}
+
+    // whether the buys of the plan are paid by the budget of the round
+    fn spends_budget(&self) -> bool {
+        false
+    }
+}
+
+// strategies a user can pick, each keeps its own state between rounds
+#[derive(Debug, Clone)]
+pub enum UserStrategy {
+    Dca(Dca),
+    Rebalance(Rebalance),
+    ValueAveraging(ValueAveraging),
+    Grid(Grid),
+    MaCrossover(MaCrossover),
+}
+
+impl Default for UserStrategy {
+    fn default() -> Self {
+        UserStrategy::Dca(Dca)
+    }
+}
+
+impl UserStrategy {
+    // `None` if the parameters are invalid
+    pub fn new(config: StrategyConfig) -> Option<Self> {
+        match config {
+            StrategyConfig::Dca => Some(UserStrategy::Dca(Dca)),
+            StrategyConfig::Rebalance { band } => Rebalance::new(band).map(UserStrategy::Rebalance),
+            StrategyConfig::ValueAveraging { step } => {
+                ValueAveraging::new(step).map(UserStrategy::ValueAveraging)
+            }
+            StrategyConfig::Grid {
+                token,
+                lower,
+                upper,
+                levels,
+                capital,
+            } => Grid::new(token, lower, upper, levels, capital).map(UserStrategy::Grid),
+            StrategyConfig::MaCrossover { short, long } => {
+                MaCrossover::new(short, long).map(UserStrategy::MaCrossover)
+            }
+        }
+    }
+
+    pub fn config(&self) -> StrategyConfig {
+        match self {
+            UserStrategy::Dca(_) => StrategyConfig::Dca,
+            UserStrategy::Rebalance(strategy) => StrategyConfig::Rebalance {
+                band: strategy.band,
+            },
+            UserStrategy::ValueAveraging(strategy) => StrategyConfig::ValueAveraging {
+                step: strategy.step,
+            },
+            UserStrategy::Grid(strategy) => strategy.config(),
+            UserStrategy::MaCrossover(strategy) => StrategyConfig::MaCrossover {
+                short: strategy.short,
+                long: strategy.long,
+            },
+        }
+    }
+
+    pub fn grid_state(&self) -> Option<GridState> {
+        match self {
+            UserStrategy::Grid(strategy) => Some(strategy.state()),
+            _ => None,
+        }
+    }
+}
+
+impl Strategy for UserStrategy {
+    fn plan(&mut self, ctx: &StrategyContext) -> Vec<Trade> {
+        match self {
+            UserStrategy::Dca(strategy) => strategy.plan(ctx),
+            UserStrategy::Rebalance(strategy) => strategy.plan(ctx),
+            UserStrategy::ValueAveraging(strategy) => strategy.plan(ctx),
+            UserStrategy::Grid(strategy) => strategy.plan(ctx),
+            UserStrategy::MaCrossover(strategy) => strategy.plan(ctx),
+        }
+    }
+
+    fn settle(&mut self, trade: &Trade, received: Option<u128>) {
+        match self {
+            UserStrategy::Dca(strategy) => strategy.settle(trade, received),
+            UserStrategy::Rebalance(strategy) => strategy.settle(trade, received),
+            UserStrategy::ValueAveraging(strategy) => strategy.settle(trade, received),
+            UserStrategy::Grid(strategy) => strategy.settle(trade, received),
+            UserStrategy::MaCrossover(strategy) => strategy.settle(trade, received),
+        }
+    }
+
+    fn spends_budget(&self) -> bool {
+        match self {
+            UserStrategy::Dca(strategy) => strategy.spends_budget(),
+            UserStrategy::Rebalance(strategy) => strategy.spends_budget(),
+            UserStrategy::ValueAveraging(strategy) => strategy.spends_budget(),
+            UserStrategy::Grid(strategy) => strategy.spends_budget(),
+            UserStrategy::MaCrossover(strategy) => strategy.spends_budget(),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use gstd::prelude::*;
+
+use super::{Strategy, StrategyContext, Trade};
+use crate::contract::{RATION_MULTIPLES, USDT_NAME};
+
+// once the value share of any token, the quote token included, drifts more than `band`
+// from its weight, sell the overweight tokens and buy the underweight ones back to target
+#[derive(Debug, Clone)]
+pub struct Rebalance {
+    // in 0.000001 of the portfolio value
+    pub band: u64,
+}
+
+impl Rebalance {
+    pub fn new(band: u64) -> Option<Self> {
+        (band as u128 <= RATION_MULTIPLES).then_some(Rebalance { band })
+    }
+}
+
+impl Strategy for Rebalance {
+    fn plan(&mut self, ctx: &StrategyContext) -> Vec<Trade> {
+        let quote_weight = ctx.portfolio.get(USDT_NAME).map_or(0, |d| d.weight);
+        let weight_sum = quote_weight + ctx.weights().map(|(_k, weight)| weight).sum::<u32>();
+        let total = ctx.total_value();
+        if weight_sum == 0 || total == 0 {
+            return vec![];
+        }
+        let target = |weight: u32| total * weight as u128 / weight_sum as u128;
+        let drifted = ctx
+            .weights()
+            .map(|(k, weight)| (k.as_str(), weight))
+            .chain([(USDT_NAME, quote_weight)])
+            .any(|(k, weight)| {
+                ctx.value(k).abs_diff(target(weight)) * RATION_MULTIPLES / total > self.band as u128
+            });
+        if !drifted {
+            return vec![];
+        }
+        // sell first so that the buys can spend the proceeds
+        let mut sells = vec![];
+        let mut buys = vec![];
+        for (k, weight) in ctx.weights() {
+            let value = ctx.value(k);
+            let target = target(weight);
+            if value > target {
+                let amount = ctx
+                    .convert(USDT_NAME, value - target, k)
+                    .unwrap_or_default()
+                    .min(ctx.amount(k));
+                sells.push(ctx.sell(k, amount));
+            } else if target > value {
+                buys.push(ctx.buy(k, target - value));
+            }
+        }
+        sells
+            .into_iter()
+            .chain(buys)
+            .filter(|trade| trade.from_amount > 0)
+            .collect()
+    }
+}
@@ -0,0 +1,49 @@
+use gstd::prelude::*;
+
+use super::{Strategy, StrategyContext, Trade};
+use crate::contract::USDT_NAME;
+
+// value averaging, the value of the tradable tokens follows a path growing by `step`
+// quote token per round from their value when the strategy was picked, each round buys
+// the shortfall by the weights or sells the excess across the tokens held
+#[derive(Debug, Clone)]
+pub struct ValueAveraging {
+    pub step: u128,
+    // start of the path, set in the first round
+    base: Option<u128>,
+    rounds: u128,
+}
+
+impl ValueAveraging {
+    pub fn new(step: u128) -> Option<Self> {
+        (step > 0).then_some(ValueAveraging {
+            step,
+            base: None,
+            rounds: 0,
+        })
+    }
+}
+
+impl Strategy for ValueAveraging {
+    fn plan(&mut self, ctx: &StrategyContext) -> Vec<Trade> {
+        let value = ctx.tokens_value();
+        let base = *self.base.get_or_insert(value);
+        self.rounds += 1;
+        let target = base + self.step * self.rounds;
+        if target >= value {
+            return ctx.buy_by_weights((target - value).min(ctx.amount(USDT_NAME)));
+        }
+        let excess = value - target;
+        ctx.prices
+            .keys()
+            .map(|k| {
+                let amount = ctx
+                    .convert(USDT_NAME, excess * ctx.value(k) / value, k)
+                    .unwrap_or_default()
+                    .min(ctx.amount(k));
+                ctx.sell(k, amount)
+            })
+            .filter(|trade| trade.from_amount > 0)
+            .collect()
+    }
+}
@@ -0,0 +1,40 @@
+use gstd::{debug, msg, prelude::*, ActorId};
+use onchainquant_io::{AmmAction, AmmEvent};
+
+// swap `amount_in` of `token_in` on the amm `dex`, returns the amount of `token_out` received,
+// the amm replies `Swapped` once its ft programs moved both sides
+pub(crate) async fn swap(
+    dex: &ActorId,
+    token_in: &str,
+    amount_in: u128,
+    token_out: &str,
+    min_out: u128,
+) -> Option<u128> {
+    let reply = match msg::send_for_reply_as::<_, AmmEvent>(
+        *dex,
+        AmmAction::Swap {
+            token_in: token_in.to_string(),
+            amount_in,
+            token_out: token_out.to_string(),
+            min_out,
+        },
+        0,
+    ) {
+        Ok(future) => future.await,
+        Err(e) => {
+            debug!("send swap to dex failed {e}");
+            return None;
+        }
+    };
+    match reply {
+        Ok(AmmEvent::Swapped { amount_out, .. }) => Some(amount_out),
+        Ok(_) => {
+            debug!("dex rejected swap of {amount_in} {token_in} for {token_out}");
+            None
+        }
+        Err(e) => {
+            debug!("swap {amount_in} {token_in} for {token_out} failed {e}");
+            None
+        }
+    }
+}
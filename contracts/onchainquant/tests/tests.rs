@@ -1,26 +1,217 @@
-use gstd::Encode;
-use gtest::{Program, System};
+use ft_main_io::{FTokenAction, FTokenEvent, InitFToken, LogicAction};
+use gstd::{ActorId, Decode, Encode};
+use gtest::{Program, RunResult, System};
 use onchainquant_io::*;
+use std::collections::BTreeMap;
 
 const USERS: &[u64] = &[3, 4, 5];
 const RATION: u64 = 100_000; // 10%
+const USDT: &str = "ocqUSDT";
+const BTC: &str = "ocqBTC";
+const ETH: &str = "ocqETH";
+const WASM_DIR: &str = "../../target/wasm32-unknown-unknown/release";
+
 fn init(sys: &System) {
     sys.init_logger();
 
     let quant = Program::current(sys);
+    init_quant(&quant, config(vec![]));
+}
 
-    let res = quant.send(
+fn config(tokens: Vec<TokenConfig>) -> InitConfig {
+    InitConfig {
+        r_invest_ration: RATION,
+        block_step: 2,
+        tokens,
+        price_source: PriceSource::Simulated,
+        twap_window: 0,
+        market: MarketModelConfig::default(),
+        dex: None,
+        // 1%
+        max_slippage: 100,
+    }
+}
+
+fn init_quant(quant: &Program, config: InitConfig) {
+    let res = quant.send(USERS[0], config);
+
+    assert!(!res.main_failed());
+    let res = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(res.r_invest_ration, RATION);
+}
+
+fn token(name: &str, program_id: ActorId, decimals: u8) -> TokenConfig {
+    TokenConfig {
+        name: name.to_string(),
+        program_id,
+        decimals,
+        enabled: true,
+        max_age: 0,
+        max_deviation: 0,
+        oracles: vec![],
+        min_quorum: 0,
+    }
+}
+
+fn ok(event: OcqEvent) -> Vec<u8> {
+    Ok::<_, OcqError>(event).encode()
+}
+
+fn err(error: OcqError) -> Vec<u8> {
+    Err::<OcqEvent, _>(error).encode()
+}
+
+fn actor_of(program: &Program) -> ActorId {
+    ActorId::new(program.id().into_bytes())
+}
+
+fn init_ft(sys: &System) -> Program {
+    let ft = Program::from_file(sys, format!("{WASM_DIR}/ft_main.opt.wasm"));
+    let storage_code_hash: [u8; 32] = sys
+        .submit_code(format!("{WASM_DIR}/ft_storage.opt.wasm"))
+        .into();
+    let ft_logic_code_hash: [u8; 32] = sys
+        .submit_code(format!("{WASM_DIR}/ft_logic.opt.wasm"))
+        .into();
+    let res = ft.send(
         USERS[0],
-        InitConfig {
-            r_invest_ration: RATION,
-            block_step: 2,
+        InitFToken {
+            storage_code_hash: storage_code_hash.into(),
+            ft_logic_code_hash: ft_logic_code_hash.into(),
         },
     );
+    assert!(!res.main_failed());
+    ft
+}
 
+fn mint(ft: &Program, transaction_id: u64, recipient: ActorId, amount: u128) {
+    let res = ft.send(
+        USERS[0],
+        FTokenAction::Message {
+            transaction_id,
+            payload: LogicAction::Mint { recipient, amount },
+        },
+    );
+    assert!(res.contains(&(USERS[0], FTokenEvent::Ok.encode())));
+}
+
+fn approve(ft: &Program, transaction_id: u64, owner: u64, approved_account: ActorId, amount: u128) {
+    let res = ft.send(
+        owner,
+        FTokenAction::Message {
+            transaction_id,
+            payload: LogicAction::Approve {
+                approved_account,
+                amount,
+            },
+        },
+    );
+    assert!(res.contains(&(owner, FTokenEvent::Ok.encode())));
+}
+
+fn invest(quant: &Program, user: u64, token: &str, amount: u128) {
+    let res = quant.send(
+        user,
+        OcqAction::Invest {
+            token: token.to_string(),
+            amount,
+        },
+    );
+    assert!(res.contains(&(
+        user,
+        ok(OcqEvent::Invest {
+            token: token.to_string(),
+            amount,
+        })
+    )));
+}
+
+fn assets(quant: &Program, user: u64) -> BTreeMap<String, u128> {
+    let res = quant.send(user, OcqAction::Asset);
+    let reply = res.log().last().expect("asset reply");
+    let reply = Result::<OcqEvent, OcqError>::decode(&mut reply.payload()).expect("decode asset");
+    match reply {
+        Ok(OcqEvent::Asset(assets)) => assets.into_iter().collect(),
+        reply => panic!("unexpected reply {reply:?}"),
+    }
+}
+
+// quant priced by a mock oracle at 25_000 USDT per `target`, USERS[1] holds 1_000 USDT to buy it
+fn init_with_oracle(sys: &System, target: TokenConfig) -> (Program, Program) {
+    let (quant, oracle, _usdt) = init_with_oracle_config(sys, target, |config| config);
+    (quant, oracle)
+}
+
+// same with its own init config, the USDT ft program comes last
+fn init_with_oracle_config(
+    sys: &System,
+    target: TokenConfig,
+    config_of: impl FnOnce(InitConfig) -> InitConfig,
+) -> (Program, Program, Program) {
+    sys.init_logger();
+    let quant = Program::current(sys);
+    let usdt = init_ft(sys);
+    let oracle = Program::from_file(sys, format!("{WASM_DIR}/mock_oracle.opt.wasm"));
+    let res = oracle.send(USERS[0], vec![(target.name.clone(), 25_000 * 1_000_000u64)]);
     assert!(!res.main_failed());
-    let res = quant.read_state::<IOOnchainQuant>().expect("state");
-    assert_eq!(res.r_invest_ration, RATION);
+    init_quant(
+        &quant,
+        config_of(InitConfig {
+            price_source: PriceSource::Oracle(actor_of(&oracle)),
+            ..config(vec![token(USDT, actor_of(&usdt), 6), target.clone()])
+        }),
+    );
+
+    mint(&usdt, 0, USERS[1].into(), 1_000 * 1_000_000);
+    approve(&usdt, 0, USERS[1], actor_of(&quant), 1_000 * 1_000_000);
+    invest(&quant, USERS[1], USDT, 1_000 * 1_000_000);
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::AssetAllocationRatio(vec![(target.name, 100)]),
+    );
+    (quant, oracle, usdt)
+}
+
+// amm with 1_000_000 USDT and 40 `token`, 25_000 USDT per `token`
+fn init_amm(sys: &System, usdt: &Program, token: &str, token_ft: &Program) -> Program {
+    let amm = Program::from_file(sys, format!("{WASM_DIR}/amm.opt.wasm"));
+    let res = amm.send(
+        USERS[0],
+        vec![AmmPool {
+            token_a: USDT.to_string(),
+            program_a: actor_of(usdt),
+            reserve_a: 1_000_000 * 1_000_000,
+            token_b: token.to_string(),
+            program_b: actor_of(token_ft),
+            reserve_b: 40 * 100_000_000,
+        }],
+    );
+    assert!(!res.main_failed());
+    mint(usdt, 1, actor_of(&amm), 1_000_000 * 1_000_000);
+    mint(token_ft, 1, actor_of(&amm), 40 * 100_000_000);
+    amm
+}
+
+// conditional orders of `user` fired in `results`
+fn fired_orders(results: &[RunResult], user: u64) -> Vec<OrderFiredMsg> {
+    results
+        .iter()
+        .flat_map(|res| res.log())
+        .filter(|log| ActorId::new(log.destination().into_bytes()) == ActorId::from(user))
+        .filter_map(|log| {
+            let mut payload = log.payload();
+            OrderFiredMsg::decode(&mut payload)
+                .ok()
+                .filter(|_| payload.is_empty())
+        })
+        .collect()
+}
+
+fn check_balance(ft: &Program, account: ActorId, amount: u128) {
+    let res = ft.send(USERS[0], FTokenAction::GetBalance(account));
+    assert!(res.contains(&(USERS[0], FTokenEvent::Balance(amount).encode())));
 }
+
 #[test]
 fn start() {
     let sys = System::new();
@@ -29,15 +220,14 @@ fn start() {
     let res = quant.send(USERS[0], OcqAction::GasReserveDefault);
     assert!(res.contains(&(
         USERS[0],
-        OcqEvent::GasReserve {
+        ok(OcqEvent::GasReserve {
             amount: 100_000,
             time: 1296000
-        }
-        .encode()
+        })
     )));
 
     let res = quant.send(USERS[0], OcqAction::Start);
-    assert!(res.contains(&(USERS[0], OcqEvent::Success.encode())));
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
     let _ = sys.spend_blocks(2);
     let res = quant.read_state::<IOOnchainQuant>().expect("state");
     assert_eq!(res.block_next, 4);
@@ -52,16 +242,15 @@ fn stop() {
     let res = quant.send(USERS[0], OcqAction::GasReserveDefault);
     assert!(res.contains(&(
         USERS[0],
-        OcqEvent::GasReserve {
+        ok(OcqEvent::GasReserve {
             amount: 100_000,
             time: 1296000
-        }
-        .encode()
+        })
     )));
     // start
     let block_height = sys.block_height();
     let res = quant.send(USERS[0], OcqAction::Start);
-    assert!(res.contains(&(USERS[0], OcqEvent::Success.encode())));
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
     let _ = sys.spend_blocks(2);
     let status0 = quant.read_state::<IOOnchainQuant>().expect("state");
     assert_eq!(status0.block_next, block_height + 2 * 2);
@@ -69,7 +258,7 @@ fn stop() {
     println!("res {:?}", res);
     // stop
     let res = quant.send(USERS[0], OcqAction::Stop);
-    assert!(res.contains(&(USERS[0], OcqEvent::Success.encode())));
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
     let _ = sys.spend_blocks(11);
     let status1 = quant.read_state::<IOOnchainQuant>().expect("state");
     assert_eq!(status1.block_next, 0);
@@ -77,7 +266,7 @@ fn stop() {
     // start again
     let block_height = sys.block_height();
     let res = quant.send(USERS[0], OcqAction::Start);
-    assert!(res.contains(&(USERS[0], OcqEvent::Success.encode())));
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
     let _ = sys.spend_blocks(2);
     let status2 = quant.read_state::<IOOnchainQuant>().expect("state");
     assert_eq!(status2.block_next, block_height + 2 * 2);
@@ -85,7 +274,7 @@ fn stop() {
     println!("res {:?}", res);
     // stop
     let res = quant.send(USERS[0], OcqAction::Stop);
-    assert!(res.contains(&(USERS[0], OcqEvent::Success.encode())));
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
     let _ = sys.spend_blocks(15);
     let status3 = quant.read_state::<IOOnchainQuant>().expect("state");
     assert_eq!(status3.block_next, 0);
@@ -98,11 +287,10 @@ fn reserve_again() {
     init(&sys);
     let quant = sys.get_program(1);
     let res = quant.send(USERS[0], OcqAction::GasReserveDefault);
-    let r = OcqEvent::GasReserve {
+    let r = ok(OcqEvent::GasReserve {
         amount: 100_000,
         time: 1296000,
-    }
-    .encode();
+    });
     assert!(res.contains(&(USERS[0], r.clone())));
     let res = quant.send(USERS[0], OcqAction::GasReserveDefault);
     let _ = sys.spend_blocks(10);
@@ -121,11 +309,10 @@ fn reserve_alert() {
             blocks: 1,
         },
     );
-    let r = OcqEvent::GasReserve {
+    let r = ok(OcqEvent::GasReserve {
         amount: 4999,
         time: 1,
-    }
-    .encode();
+    });
     assert!(res.contains(&(USERS[0], r.clone())));
     let _res = quant.send(USERS[0], OcqAction::Start);
     let _ = sys.spend_blocks(10);
@@ -140,3 +327,1409 @@ fn reserve_alert() {
         .encode(),
     ));
 }
+
+#[test]
+fn withdraw() {
+    let sys = System::new();
+    sys.init_logger();
+    let quant = Program::current(&sys);
+    let ft = init_ft(&sys);
+    init_quant(&quant, config(vec![token(USDT, actor_of(&ft), 6)]));
+    mint(&ft, 0, USERS[1].into(), 1_000);
+    approve(&ft, 0, USERS[1], actor_of(&quant), 1_000);
+    invest(&quant, USERS[1], USDT, 1_000);
+
+    let res = quant.send(
+        USERS[1],
+        OcqAction::WithDraw {
+            token: USDT.to_string(),
+            amount: 400,
+        },
+    );
+    assert!(res.contains(&(
+        USERS[1],
+        ok(OcqEvent::WithDraw {
+            token: USDT.to_string(),
+            amount: 400,
+        })
+    )));
+    check_balance(&ft, USERS[1].into(), 400);
+    check_balance(&ft, actor_of(&quant), 600);
+
+    // more than the remaining deposit
+    let res = quant.send(
+        USERS[1],
+        OcqAction::WithDraw {
+            token: USDT.to_string(),
+            amount: 601,
+        },
+    );
+    assert!(res.contains(&(USERS[1], err(OcqError::InsufficientBalance))));
+    check_balance(&ft, USERS[1].into(), 400);
+}
+
+#[test]
+fn invest_needs_approval() {
+    let sys = System::new();
+    sys.init_logger();
+    let quant = Program::current(&sys);
+    let ft = init_ft(&sys);
+    init_quant(&quant, config(vec![token(USDT, actor_of(&ft), 6)]));
+    mint(&ft, 0, USERS[1].into(), 1_000);
+
+    // no account starts with a balance the ft program has not confirmed
+    for user in USERS {
+        let res = quant.send(*user, OcqAction::Asset);
+        assert!(res.contains(&(*user, ok(OcqEvent::Asset(vec![])))));
+    }
+    let res = quant.send(
+        USERS[0],
+        OcqAction::WithDraw {
+            token: USDT.to_string(),
+            amount: 1,
+        },
+    );
+    assert!(res.contains(&(USERS[0], err(OcqError::InsufficientBalance))));
+
+    // nothing is credited without an approval
+    let res = quant.send(
+        USERS[1],
+        OcqAction::Invest {
+            token: USDT.to_string(),
+            amount: 500,
+        },
+    );
+    assert!(res.contains(&(USERS[1], err(OcqError::TransferFailed))));
+    let res = quant.send(USERS[1], OcqAction::Asset);
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Asset(vec![])))));
+
+    approve(&ft, 0, USERS[1], actor_of(&quant), 500);
+    invest(&quant, USERS[1], USDT, 500);
+    check_balance(&ft, USERS[1].into(), 500);
+    check_balance(&ft, actor_of(&quant), 500);
+    let res = quant.send(USERS[1], OcqAction::Asset);
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Asset(vec![(USDT.to_string(), 500)])))));
+
+    // the ft program and the decimals of a held token are fixed
+    let res = quant.send(
+        USERS[0],
+        OcqAction::UpdateToken(token(USDT, ActorId::from(100u64), 6)),
+    );
+    assert!(res.contains(&(USERS[0], err(OcqError::TokenInUse(USDT.to_string())))));
+    let res = quant.send(
+        USERS[0],
+        OcqAction::UpdateToken(token(USDT, actor_of(&ft), 8)),
+    );
+    assert!(res.contains(&(USERS[0], err(OcqError::TokenInUse(USDT.to_string())))));
+    let res = quant.send(
+        USERS[0],
+        OcqAction::UpdateToken(TokenConfig {
+            max_age: 60_000,
+            ..token(USDT, actor_of(&ft), 6)
+        }),
+    );
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
+}
+
+#[test]
+fn token_registry() {
+    let sys = System::new();
+    init(&sys);
+    let quant = sys.get_program(1);
+    let btc = token(BTC, ActorId::from(100u64), 8);
+
+    // only the owner manages tokens
+    let res = quant.send(USERS[1], OcqAction::RegisterToken(btc.clone()));
+    assert!(res.contains(&(USERS[1], err(OcqError::Unauthorized))));
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert!(state.tokens.is_empty());
+
+    let res = quant.send(
+        USERS[0],
+        OcqAction::RegisterToken(TokenConfig {
+            decimals: 20,
+            ..btc.clone()
+        }),
+    );
+    assert!(res.contains(&(USERS[0], err(OcqError::InvalidParameters))));
+    let res = quant.send(USERS[0], OcqAction::RegisterToken(btc.clone()));
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.tokens, vec![btc.clone()]);
+
+    let disabled = TokenConfig {
+        enabled: false,
+        ..btc.clone()
+    };
+    let _ = quant.send(USERS[0], OcqAction::UpdateToken(disabled.clone()));
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.tokens, vec![disabled]);
+
+    let _ = quant.send(USERS[0], OcqAction::DelistToken(btc.name));
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert!(state.tokens.is_empty());
+}
+
+#[test]
+fn oracle_price() {
+    let sys = System::new();
+    let (quant, _oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let res = quant.send(USERS[0], OcqAction::Start);
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
+    // 10% of the USDT buys 100 / 25_000 BTC
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 900 * 1_000_000);
+    assert_eq!(assets[BTC], 400_000);
+    // dca by default, without picking it
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert!(state.strategies.is_empty());
+}
+
+#[test]
+fn zero_oracle_price() {
+    let sys = System::new();
+    let (quant, oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    let _ = oracle.send(
+        USERS[0],
+        OracleAction::SetPrice {
+            token: BTC.to_string(),
+            price: 0,
+        },
+    );
+
+    // no price, no trade, the rounds go on
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let res = quant.send(USERS[0], OcqAction::Start);
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
+    assert_eq!(assets(&quant, USERS[1])[BTC], 0);
+    let _ = oracle.send(
+        USERS[0],
+        OracleAction::SetPrice {
+            token: BTC.to_string(),
+            price: 25_000 * 1_000_000,
+        },
+    );
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+}
+
+#[test]
+fn price_deviation_guard() {
+    let sys = System::new();
+    let btc = TokenConfig {
+        // 10%
+        max_deviation: 100_000,
+        ..token(BTC, ActorId::zero(), 8)
+    };
+    let (quant, oracle) = init_with_oracle(&sys, btc);
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+
+    // a 20% jump is not traded
+    let _ = oracle.send(
+        USERS[0],
+        OracleAction::SetPrice {
+            token: BTC.to_string(),
+            price: 30_000 * 1_000_000,
+        },
+    );
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.price_alerts.len(), 1);
+    let alert = &state.price_alerts[0];
+    assert_eq!(alert.action_id, 1);
+    assert_eq!(alert.last_price, 25_000 * 1_000_000);
+    assert_eq!(alert.reason, PriceAlertReason::Deviation);
+
+    // the move is real, an operator accepts the new level
+    let res = quant.send(USERS[1], OcqAction::ResetPrice(BTC.to_string()));
+    assert!(res.contains(&(USERS[1], err(OcqError::Unauthorized))));
+    let res = quant.send(USERS[0], OcqAction::ResetPrice(BTC.to_string()));
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
+    let _ = sys.spend_blocks(2);
+    // 10% of the 900 USDT left buys 90 / 30_000 BTC
+    assert_eq!(assets(&quant, USERS[1])[BTC], 700_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.price_alerts.len(), 1);
+}
+
+#[test]
+fn median_of_oracles() {
+    let sys = System::new();
+    let btc = token(BTC, ActorId::zero(), 8);
+    let (quant, _oracle) = init_with_oracle(&sys, btc.clone());
+    let oracles: Vec<ActorId> = [24_000, 25_000, 40_000]
+        .into_iter()
+        .map(|price| {
+            let oracle = Program::from_file(&sys, format!("{WASM_DIR}/mock_oracle.opt.wasm"));
+            let res = oracle.send(USERS[0], vec![(BTC.to_string(), price * 1_000_000u64)]);
+            assert!(!res.main_failed());
+            actor_of(&oracle)
+        })
+        .collect();
+    let btc = TokenConfig {
+        oracles: oracles.clone(),
+        min_quorum: 2,
+        ..btc
+    };
+    let _ = quant.send(USERS[0], OcqAction::UpdateToken(btc.clone()));
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert!(state.price_rounds.contains(&PriceRound {
+        action_id: 0,
+        token: BTC.to_string(),
+        price: 25_000 * 1_000_000,
+        sources: oracles,
+    }));
+
+    // more answers needed than oracles
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::UpdateToken(TokenConfig {
+            min_quorum: 4,
+            ..btc
+        }),
+    );
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.price_alerts.len(), 1);
+    assert_eq!(state.price_alerts[0].reason, PriceAlertReason::NoQuorum);
+}
+
+#[test]
+fn price_history_twap() {
+    let sys = System::new();
+    let (quant, oracle, _usdt) =
+        init_with_oracle_config(&sys, token(BTC, ActorId::zero(), 8), |config| InitConfig {
+            twap_window: 60 * 60 * 1000,
+            ..config
+        });
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+    let _ = oracle.send(
+        USERS[0],
+        OracleAction::SetPrice {
+            token: BTC.to_string(),
+            price: 35_000 * 1_000_000,
+        },
+    );
+    let _ = sys.spend_blocks(2);
+
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    let (_, history) = state
+        .price_history
+        .iter()
+        .find(|(token, _)| token == BTC)
+        .expect("btc history");
+    let prices: Vec<u64> = history.iter().map(|o| o.price).collect();
+    assert_eq!(prices, vec![25_000 * 1_000_000, 35_000 * 1_000_000]);
+    // the new price has not been held for any time yet
+    assert!(state.twap.contains(&(BTC.to_string(), 25_000 * 1_000_000)));
+    // 10% of the remaining 900 USDT bought at the twap
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000 + 360_000);
+}
+
+#[test]
+fn simulated_market_drift() {
+    let sys = System::new();
+    sys.init_logger();
+    let quant = Program::current(&sys);
+    init_quant(
+        &quant,
+        InitConfig {
+            market: MarketModelConfig {
+                tokens: vec![SimulatedToken {
+                    name: BTC.to_string(),
+                    price: 25_000 * 1_000_000,
+                    // 1% per round without noise
+                    drift: 10_000,
+                    volatility: 0,
+                }],
+                correlation: 0,
+            },
+            ..config(vec![])
+        },
+    );
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.market.tokens[0].price, 25_250 * 1_000_000);
+    let _ = sys.spend_blocks(2);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.market.tokens[0].price, 25_502_500_000);
+}
+
+#[test]
+fn swap_on_amm() {
+    let sys = System::new();
+    let eth = init_ft(&sys);
+    let (quant, _oracle, usdt) =
+        init_with_oracle_config(&sys, token(ETH, actor_of(&eth), 8), |config| config);
+    let amm = init_amm(&sys, &usdt, ETH, &eth);
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::UpdateConfig(ConfigParam::Dex(Some(actor_of(&amm)))),
+    );
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    // 100 USDT through the pool, less than 400_000 at the oracle price after the fee and the price impact
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 900 * 1_000_000);
+    assert_eq!(assets[ETH], 398_760);
+    check_balance(&usdt, actor_of(&quant), 900 * 1_000_000);
+    check_balance(&usdt, actor_of(&amm), 1_000_100 * 1_000_000);
+    check_balance(&eth, actor_of(&quant), 398_760);
+
+    // the bought tokens are in the program
+    let res = quant.send(
+        USERS[1],
+        OcqAction::WithDraw {
+            token: ETH.to_string(),
+            amount: 398_760,
+        },
+    );
+    assert!(res.contains(&(
+        USERS[1],
+        ok(OcqEvent::WithDraw {
+            token: ETH.to_string(),
+            amount: 398_760,
+        })
+    )));
+    check_balance(&eth, USERS[1].into(), 398_760);
+}
+
+#[test]
+fn slippage_limit() {
+    let sys = System::new();
+    let eth = init_ft(&sys);
+    let (quant, _oracle, usdt) =
+        init_with_oracle_config(&sys, token(ETH, actor_of(&eth), 8), |config| config);
+    let amm = init_amm(&sys, &usdt, ETH, &eth);
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::UpdateConfig(ConfigParam::Dex(Some(actor_of(&amm)))),
+    );
+    // the pool gives 0.31% less than the oracle price
+    let _ = quant.send(USERS[1], OcqAction::MaxSlippage(Some(10)));
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    let assets_of = assets(&quant, USERS[1]);
+    assert_eq!(assets_of[USDT], 1_000 * 1_000_000);
+    assert_eq!(assets_of[ETH], 0);
+    // no token moved
+    check_balance(&usdt, actor_of(&quant), 1_000 * 1_000_000);
+    check_balance(&usdt, actor_of(&amm), 1_000_000 * 1_000_000);
+
+    // back to the global 1%
+    let _ = quant.send(USERS[1], OcqAction::MaxSlippage(None));
+    let _ = sys.spend_blocks(2);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 900 * 1_000_000);
+    assert_eq!(assets[ETH], 398_760);
+    check_balance(&eth, actor_of(&quant), 398_760);
+}
+
+#[test]
+fn slippage_from_spot() {
+    let sys = System::new();
+    let eth = init_ft(&sys);
+    let (quant, oracle, usdt) =
+        init_with_oracle_config(&sys, token(ETH, actor_of(&eth), 8), |config| InitConfig {
+            twap_window: 60 * 60 * 1000,
+            ..config
+        });
+    let amm = init_amm(&sys, &usdt, ETH, &eth);
+    let set_price = |price: u64| {
+        let _ = oracle.send(
+            USERS[0],
+            OracleAction::SetPrice {
+                token: ETH.to_string(),
+                price: price * 1_000_000,
+            },
+        );
+    };
+
+    // nothing traded without the dex, the price is kept in the history
+    set_price(20_000);
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[ETH], 0);
+
+    // the twap still says 20_000, the pool follows the spot price
+    set_price(25_000);
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::UpdateConfig(ConfigParam::Dex(Some(actor_of(&amm)))),
+    );
+    let _ = sys.spend_blocks(2);
+    let assets_of = assets(&quant, USERS[1]);
+    assert_eq!(assets_of[USDT], 900 * 1_000_000);
+    assert_eq!(assets_of[ETH], 398_760);
+    check_balance(&eth, actor_of(&quant), 398_760);
+}
+
+#[test]
+fn book_trade_needs_dex() {
+    let sys = System::new();
+    let eth = init_ft(&sys);
+    let (quant, _oracle, _usdt) =
+        init_with_oracle_config(&sys, token(ETH, actor_of(&eth), 8), |config| config);
+    mint(&eth, 0, USERS[2].into(), 100_000_000);
+    approve(&eth, 0, USERS[2], actor_of(&quant), 100_000_000);
+    invest(&quant, USERS[2], ETH, 100_000_000);
+
+    // the ETH deposited is not sold in the books
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    let assets_of = assets(&quant, USERS[1]);
+    assert_eq!(assets_of[USDT], 1_000 * 1_000_000);
+    assert_eq!(assets_of[ETH], 0);
+    let res = quant.send(
+        USERS[2],
+        OcqAction::WithDraw {
+            token: ETH.to_string(),
+            amount: 100_000_000,
+        },
+    );
+    assert!(res.contains(&(
+        USERS[2],
+        ok(OcqEvent::WithDraw {
+            token: ETH.to_string(),
+            amount: 100_000_000,
+        })
+    )));
+    check_balance(&eth, USERS[2].into(), 100_000_000);
+}
+
+#[test]
+fn set_strategy() {
+    let sys = System::new();
+    init(&sys);
+    let quant = sys.get_program(1);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert!(state.strategies.is_empty());
+
+    let res = quant.send(USERS[1], OcqAction::SetStrategy(StrategyConfig::Dca));
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Success))));
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(
+        state.strategies,
+        vec![(ActorId::from(USERS[1]), StrategyConfig::Dca)]
+    );
+}
+
+#[test]
+fn rebalance() {
+    let sys = System::new();
+    let (quant, oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    // half USDT, half BTC, trade once a share is 5% off
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::AssetAllocationRatio(vec![(USDT.to_string(), 100), (BTC.to_string(), 100)]),
+    );
+    let res = quant.send(
+        USERS[1],
+        OcqAction::SetStrategy(StrategyConfig::Rebalance { band: 50_000 }),
+    );
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Success))));
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 500 * 1_000_000);
+    assert_eq!(assets[BTC], 2_000_000);
+
+    let set_price = |price: u64| {
+        let _ = oracle.send(
+            USERS[0],
+            OracleAction::SetPrice {
+                token: BTC.to_string(),
+                price: price * 1_000_000,
+            },
+        );
+        let _ = sys.spend_blocks(2);
+    };
+    // BTC is 54.5% of the value, inside the band
+    set_price(30_000);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 2_000_000);
+
+    // BTC is 58.3% of the value, 100 USDT of it is sold
+    set_price(35_000);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 599_999_900);
+    assert_eq!(assets[BTC], 1_714_286);
+}
+
+#[test]
+fn value_averaging() {
+    let sys = System::new();
+    let (quant, oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    // the BTC held is worth 100 USDT more every round
+    let res = quant.send(
+        USERS[1],
+        OcqAction::SetStrategy(StrategyConfig::ValueAveraging {
+            step: 100 * 1_000_000,
+        }),
+    );
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Success))));
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 900 * 1_000_000);
+    assert_eq!(assets[BTC], 400_000);
+
+    let set_price = |price: u64| {
+        let _ = oracle.send(
+            USERS[0],
+            OracleAction::SetPrice {
+                token: BTC.to_string(),
+                price: price * 1_000_000,
+            },
+        );
+        let _ = sys.spend_blocks(2);
+    };
+    // worth 120 USDT for a target of 200, 80 USDT more is bought
+    set_price(30_000);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 820 * 1_000_000);
+    assert_eq!(assets[BTC], 666_666);
+
+    // worth 333.333 USDT for a target of 300, the excess is sold
+    set_price(50_000);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 853_333_000);
+    assert_eq!(assets[BTC], 600_000);
+}
+
+#[test]
+fn dip_budget() {
+    let sys = System::new();
+    let (quant, oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    // twice the budget 10% below the average cost, three times 20% below,
+    // never more than 25% of the USDT in a round
+    let rule = DipRule {
+        reference: DipReference::AverageCost,
+        tiers: vec![(100_000, 2_000_000), (200_000, 3_000_000)],
+        max_budget: 250_000,
+    };
+    let res = quant.send(USERS[1], OcqAction::SetDipRule(Some(rule.clone())));
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Success))));
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(
+        state.dip_rules,
+        vec![(ActorId::from(USERS[1]), rule.clone())]
+    );
+
+    // nothing held yet, the budget is not scaled
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 900 * 1_000_000);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+
+    // 20% below the average cost of 25_000, 270 USDT capped to 225 USDT
+    let _ = oracle.send(
+        USERS[0],
+        OracleAction::SetPrice {
+            token: BTC.to_string(),
+            price: 20_000 * 1_000_000,
+        },
+    );
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 675 * 1_000_000);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 1_525_000);
+
+    // a cap below the usual 10% does not lower the budget, 6% below the average cost is no dip
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::SetDipRule(Some(DipRule {
+            max_budget: 50_000,
+            ..rule
+        })),
+    );
+    let _ = sys.spend_blocks(2);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 607_500_000);
+    assert_eq!(assets[BTC], 1_862_500);
+}
+
+#[test]
+fn scheduled_sell() {
+    let sys = System::new();
+    let (quant, oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+
+    // stop buying, sell half the BTC each round above 20_000 until 300_000 is sold
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::AssetAllocationRatio(vec![(BTC.to_string(), 0)]),
+    );
+    let res = quant.send(
+        USERS[1],
+        OcqAction::ScheduleSell {
+            token: BTC.to_string(),
+            ratio: 500_000,
+            floor: Some(20_000 * 1_000_000),
+            target: 300_000,
+        },
+    );
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Success))));
+
+    let _ = sys.spend_blocks(2);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 950 * 1_000_000);
+    assert_eq!(assets[BTC], 200_000);
+
+    let set_price = |price: u64| {
+        let _ = oracle.send(
+            USERS[0],
+            OracleAction::SetPrice {
+                token: BTC.to_string(),
+                price: price * 1_000_000,
+            },
+        );
+        let _ = sys.spend_blocks(2);
+    };
+    // below the floor
+    set_price(19_000);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 200_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.sell_schedules[0].1.sold, 200_000);
+
+    // only the 100_000 left to the target is sold, then the schedule ends
+    set_price(30_000);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 980 * 1_000_000);
+    assert_eq!(assets[BTC], 100_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert!(state.sell_schedules.is_empty());
+}
+
+#[test]
+fn conditional_orders() {
+    let sys = System::new();
+    let (quant, oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+
+    // stop buying, take a quarter of the profit at 40_000,
+    // sell the rest 10% below the highest price
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::AssetAllocationRatio(vec![(BTC.to_string(), 0)]),
+    );
+    let res = quant.send(
+        USERS[1],
+        OcqAction::PlaceOrder {
+            token: BTC.to_string(),
+            kind: OrderKind::TakeProfit {
+                price: 40_000 * 1_000_000,
+            },
+            amount: 100_000,
+        },
+    );
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::OrderPlaced { id: 1 }))));
+    let res = quant.send(
+        USERS[1],
+        OcqAction::PlaceOrder {
+            token: BTC.to_string(),
+            kind: OrderKind::TrailingStop { distance: 100_000 },
+            amount: 0,
+        },
+    );
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::OrderPlaced { id: 2 }))));
+    let res = quant.send(
+        USERS[1],
+        OcqAction::PlaceOrder {
+            token: USDT.to_string(),
+            kind: OrderKind::StopLoss { price: 1 },
+            amount: 0,
+        },
+    );
+    assert!(res.contains(&(USERS[1], err(OcqError::UnknownToken(USDT.to_string())))));
+
+    let set_price = |price: u64| {
+        let _ = oracle.send(
+            USERS[0],
+            OracleAction::SetPrice {
+                token: BTC.to_string(),
+                price: price * 1_000_000,
+            },
+        );
+        sys.spend_blocks(2)
+    };
+    let _ = set_price(40_000);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 940 * 1_000_000);
+    assert_eq!(assets[BTC], 300_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.orders.len(), 1);
+    assert_eq!(state.orders[0].1.peak, 40_000 * 1_000_000);
+
+    // 12.5% below the peak
+    let results = set_price(35_000);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 1_045 * 1_000_000);
+    assert_eq!(assets[BTC], 0);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert!(state.orders.is_empty());
+    let fired = fired_orders(&results, USERS[1]);
+    assert_eq!(fired.len(), 1);
+    assert_eq!(
+        fired[0].encode(),
+        OrderFiredMsg {
+            order: ConditionalOrder {
+                id: 2,
+                token: BTC.to_string(),
+                kind: OrderKind::TrailingStop { distance: 100_000 },
+                amount: 0,
+                peak: 40_000 * 1_000_000,
+            },
+            price: 35_000 * 1_000_000,
+            amount: 300_000,
+            status: TradeStatus::Filled,
+        }
+        .encode()
+    );
+
+    // nothing to sell, the order waits
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::PlaceOrder {
+            token: BTC.to_string(),
+            kind: OrderKind::StopLoss {
+                price: 36_000 * 1_000_000,
+            },
+            amount: 0,
+        },
+    );
+    let results = sys.spend_blocks(2);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.orders.len(), 1);
+    assert_eq!(state.orders[0].1.id, 3);
+    assert!(fired_orders(&results, USERS[1]).is_empty());
+}
+
+#[test]
+fn grid() {
+    let sys = System::new();
+    let (quant, oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    // levels at 20_000, 25_000 and 30_000
+    let res = quant.send(
+        USERS[1],
+        OcqAction::SetStrategy(StrategyConfig::Grid {
+            token: BTC.to_string(),
+            lower: 20_000 * 1_000_000,
+            upper: 30_000 * 1_000_000,
+            levels: 3,
+            capital: 100 * 1_000_000,
+        }),
+    );
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Success))));
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 1_000 * 1_000_000);
+
+    let set_price = |price: u64| {
+        let _ = oracle.send(
+            USERS[0],
+            OracleAction::SetPrice {
+                token: BTC.to_string(),
+                price: price * 1_000_000,
+            },
+        );
+        let _ = sys.spend_blocks(2);
+    };
+    // down through 20_000
+    set_price(20_000);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 900 * 1_000_000);
+    assert_eq!(assets[BTC], 500_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    let (user, grid) = &state.grids[0];
+    assert_eq!(*user, ActorId::from(USERS[1]));
+    assert_eq!(grid.levels[0], (20_000 * 1_000_000, 500_000));
+
+    // up through 25_000, the level bought at 20_000 is sold
+    set_price(26_000);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 1_030 * 1_000_000);
+    assert_eq!(assets[BTC], 0);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    let grid = &state.grids[0].1;
+    assert_eq!(grid.levels[0].1, 0);
+    assert_eq!(grid.last_price, 26_000 * 1_000_000);
+    assert_eq!(grid.profit, 30 * 1_000_000);
+}
+
+#[test]
+fn ma_crossover() {
+    let sys = System::new();
+    let (quant, oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    // the short average is the price of the last round, the long one covers the history
+    let res = quant.send(
+        USERS[1],
+        OcqAction::SetStrategy(StrategyConfig::MaCrossover {
+            short: 1,
+            long: 60 * 60 * 1000,
+        }),
+    );
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Success))));
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+
+    let set_price = |price: u64| {
+        let _ = oracle.send(
+            USERS[0],
+            OracleAction::SetPrice {
+                token: BTC.to_string(),
+                price: price * 1_000_000,
+            },
+        );
+        let _ = sys.spend_blocks(2);
+    };
+    // the averages see a new price one round later
+    set_price(30_000);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 1_000 * 1_000_000);
+    // 30_000 crosses above 27_500, all the USDT goes to BTC
+    set_price(30_000);
+    let assets_after_cross = assets(&quant, USERS[1]);
+    assert_eq!(assets_after_cross[USDT], 0);
+    assert_eq!(assets_after_cross[BTC], 3_333_333);
+
+    set_price(20_000);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 3_333_333);
+    // 20_000 crosses below 26_250, the BTC is sold
+    set_price(20_000);
+    let assets = assets(&quant, USERS[1]);
+    assert_eq!(assets[USDT], 666_666_600);
+    assert_eq!(assets[BTC], 0);
+}
+
+#[test]
+fn dca_plan() {
+    let sys = System::new();
+    let (quant, _oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    // 150 USDT in each of two rounds
+    let res = quant.send(
+        USERS[1],
+        OcqAction::SetDcaPlan {
+            budget: Budget::Fixed(150 * 1_000_000),
+            installments: Some(2),
+            interval: 0,
+            start_block: 0,
+        },
+    );
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Success))));
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 850 * 1_000_000);
+    let _ = sys.spend_blocks(2);
+    let assets_after_plan = assets(&quant, USERS[1]);
+    assert_eq!(assets_after_plan[USDT], 700 * 1_000_000);
+    assert_eq!(assets_after_plan[BTC], 1_200_000);
+    let mailbox = sys.get_mailbox(USERS[1]);
+    assert!(mailbox.contains(&(USERS[1], PlanCompletedMsg { installments: 2 }.encode())));
+
+    // the complete plan invests no more
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 700 * 1_000_000);
+
+    // 10% of 700 USDT is raised to the 100 USDT minimum
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::SetDcaPlan {
+            budget: Budget::Ratio {
+                ratio: 100_000,
+                min: 100 * 1_000_000,
+                max: 200 * 1_000_000,
+            },
+            installments: None,
+            interval: 0,
+            start_block: 0,
+        },
+    );
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 600 * 1_000_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.dca_plans[0].1.paid, 1);
+
+    // a round that buys nothing is no installment
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::AssetAllocationRatio(vec![(BTC.to_string(), 0)]),
+    );
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 600 * 1_000_000);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.dca_plans[0].1.paid, 1);
+}
+
+#[test]
+fn dca_schedule() {
+    let sys = System::new();
+    let (quant, _oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 900 * 1_000_000);
+
+    // 50 USDT every 4 blocks from the next round, rounds run every 2 blocks
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    let start_block = state.block_next;
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::SetDcaPlan {
+            budget: Budget::Fixed(50 * 1_000_000),
+            installments: None,
+            interval: 4,
+            start_block,
+        },
+    );
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 850 * 1_000_000);
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 850 * 1_000_000);
+    let _ = sys.spend_blocks(2);
+    assert_eq!(assets(&quant, USERS[1])[USDT], 800 * 1_000_000);
+
+    let res = quant.send(USERS[1], OcqAction::DcaPlan);
+    let plan = DcaPlan {
+        budget: Budget::Fixed(50 * 1_000_000),
+        installments: None,
+        paid: 2,
+        interval: 4,
+        start_block,
+        next_block: start_block + 8,
+    };
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::DcaPlan(Some(plan))))));
+}
+
+#[test]
+fn update_config() {
+    let sys = System::new();
+    init(&sys);
+    let quant = sys.get_program(1);
+
+    // only an operator, only valid values
+    let res = quant.send(
+        USERS[1],
+        OcqAction::UpdateConfig(ConfigParam::InvestRation(200_000)),
+    );
+    assert!(res.contains(&(USERS[1], err(OcqError::Unauthorized))));
+    let res = quant.send(
+        USERS[0],
+        OcqAction::UpdateConfig(ConfigParam::InvestRation(2_000_000)),
+    );
+    assert!(res.contains(&(USERS[0], err(OcqError::InvalidParameters))));
+    let res = quant.send(USERS[0], OcqAction::UpdateConfig(ConfigParam::BlockStep(0)));
+    assert!(res.contains(&(USERS[0], err(OcqError::InvalidParameters))));
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.r_invest_ration, RATION);
+    assert_eq!(state.block_step, 2);
+    assert!(state.config_log.is_empty());
+
+    let res = quant.send(
+        USERS[0],
+        OcqAction::UpdateConfig(ConfigParam::InvestRation(200_000)),
+    );
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.r_invest_ration, 200_000);
+    assert_eq!(state.config_log.len(), 1);
+    let change = &state.config_log[0];
+    assert_eq!(change.previous, ConfigParam::InvestRation(RATION));
+    assert_eq!(change.current, ConfigParam::InvestRation(200_000));
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::ConfigChanged(change.clone())))));
+
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::UpdateConfig(ConfigParam::BlockStep(10)),
+    );
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.block_step, 10);
+    assert_eq!(state.config_log.len(), 2);
+}
+
+#[test]
+fn error_replies() {
+    let sys = System::new();
+    init(&sys);
+    let quant = sys.get_program(1);
+
+    let res = quant.send(USERS[0], OcqAction::Stop);
+    assert!(res.contains(&(USERS[0], err(OcqError::NotRunning))));
+    let res = quant.send(USERS[0], OcqAction::Start);
+    assert!(res.contains(&(USERS[0], err(OcqError::NoReservation))));
+    let res = quant.send(USERS[1], OcqAction::Start);
+    assert!(res.contains(&(USERS[1], err(OcqError::Unauthorized))));
+
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    let res = quant.send(USERS[0], OcqAction::Start);
+    assert!(res.contains(&(USERS[0], err(OcqError::AlreadyRunning))));
+
+    let res = quant.send(USERS[1], OcqAction::AssetAllocationRatio(vec![]));
+    assert!(res.contains(&(USERS[1], err(OcqError::InvalidWeights))));
+    let res = quant.send(
+        USERS[1],
+        OcqAction::AssetAllocationRatio(vec![(BTC.to_string(), 100)]),
+    );
+    assert!(res.contains(&(USERS[1], err(OcqError::UnknownToken(BTC.to_string())))));
+    let res = quant.send(
+        USERS[1],
+        OcqAction::Invest {
+            token: BTC.to_string(),
+            amount: 100,
+        },
+    );
+    assert!(res.contains(&(USERS[1], err(OcqError::UnknownToken(BTC.to_string())))));
+    let res = quant.send(USERS[1], OcqAction::CancelOrder(1));
+    assert!(res.contains(&(USERS[1], err(OcqError::UnknownOrder(1)))));
+}
+
+#[test]
+fn roles() {
+    let sys = System::new();
+    let (quant, _oracle) = init_with_oracle(&sys, token(BTC, ActorId::zero(), 8));
+    let holders = |role: Role| -> Vec<ActorId> {
+        let state = quant.read_state::<IOOnchainQuant>().expect("state");
+        state
+            .roles
+            .into_iter()
+            .find(|(r, _)| *r == role)
+            .map(|(_, holders)| holders)
+            .unwrap_or_default()
+    };
+    assert_eq!(holders(Role::Admin), vec![ActorId::from(USERS[0])]);
+
+    // only an admin grants and revokes roles
+    let res = quant.send(
+        USERS[1],
+        OcqAction::GrantRole {
+            role: Role::Operator,
+            account: USERS[1].into(),
+        },
+    );
+    assert!(res.contains(&(USERS[1], err(OcqError::Unauthorized))));
+    let res = quant.send(
+        USERS[0],
+        OcqAction::RevokeRole {
+            role: Role::Admin,
+            account: USERS[0].into(),
+        },
+    );
+    assert!(res.contains(&(USERS[0], err(OcqError::LastAdmin))));
+    let res = quant.send(USERS[1], OcqAction::Terminate);
+    assert!(res.contains(&(USERS[1], err(OcqError::Unauthorized))));
+
+    // prices pushed by a feeder
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::UpdateConfig(ConfigParam::PriceSource(PriceSource::Feed)),
+    );
+    let res = quant.send(
+        USERS[2],
+        OcqAction::FeedPrice {
+            token: BTC.to_string(),
+            price: 20_000 * 1_000_000,
+        },
+    );
+    assert!(res.contains(&(USERS[2], err(OcqError::Unauthorized))));
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::GrantRole {
+            role: Role::PriceFeeder,
+            account: USERS[2].into(),
+        },
+    );
+    let res = quant.send(
+        USERS[2],
+        OcqAction::FeedPrice {
+            token: BTC.to_string(),
+            price: 20_000 * 1_000_000,
+        },
+    );
+    assert!(res.contains(&(USERS[2], ok(OcqEvent::Success))));
+
+    // a pauser stops what an operator started
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::GrantRole {
+            role: Role::Pauser,
+            account: USERS[2].into(),
+        },
+    );
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let res = quant.send(USERS[2], OcqAction::Start);
+    assert!(res.contains(&(USERS[2], err(OcqError::Unauthorized))));
+    let res = quant.send(USERS[0], OcqAction::Start);
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Success))));
+    // 10% of the USDT buys 100 / 20_000 BTC
+    assert_eq!(assets(&quant, USERS[1])[BTC], 500_000);
+    let res = quant.send(USERS[2], OcqAction::Stop);
+    assert!(res.contains(&(USERS[2], ok(OcqEvent::Success))));
+
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::RevokeRole {
+            role: Role::Pauser,
+            account: USERS[2].into(),
+        },
+    );
+    assert_eq!(holders(Role::Pauser), vec![ActorId::from(USERS[0])]);
+}
+
+#[test]
+fn ownership() {
+    let sys = System::new();
+    init(&sys);
+    let quant = sys.get_program(1);
+    let owner = ActorId::from(USERS[0]);
+    let proposed = ActorId::from(USERS[1]);
+
+    let res = quant.send(
+        USERS[1],
+        OcqAction::ProposeOwner {
+            owner: proposed,
+            blocks: 5,
+        },
+    );
+    assert!(res.contains(&(USERS[1], err(OcqError::Unauthorized))));
+    let res = quant.send(USERS[1], OcqAction::AcceptOwnership);
+    assert!(res.contains(&(USERS[1], err(OcqError::NoPendingOwner))));
+
+    // an offer not accepted in time expires
+    let _ = quant.send(
+        USERS[0],
+        OcqAction::ProposeOwner {
+            owner: proposed,
+            blocks: 5,
+        },
+    );
+    let _ = sys.spend_blocks(6);
+    let res = quant.send(USERS[1], OcqAction::AcceptOwnership);
+    assert!(res.contains(&(USERS[1], err(OcqError::OwnershipExpired))));
+
+    let res = quant.send(
+        USERS[0],
+        OcqAction::ProposeOwner {
+            owner: proposed,
+            blocks: 5,
+        },
+    );
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    let pending = state.pending_owner.expect("pending owner");
+    assert_eq!(pending.owner, proposed);
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::OwnershipProposed(pending)))));
+    let res = quant.send(USERS[2], OcqAction::AcceptOwnership);
+    assert!(res.contains(&(USERS[2], err(OcqError::Unauthorized))));
+    let res = quant.send(USERS[1], OcqAction::AcceptOwnership);
+    assert!(res.contains(&(
+        USERS[1],
+        ok(OcqEvent::OwnershipTransferred {
+            previous: owner,
+            current: proposed,
+        })
+    )));
+
+    // the roles moved with the ownership
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.owner, proposed);
+    assert!(state.pending_owner.is_none());
+    assert!(state
+        .roles
+        .iter()
+        .all(|(_, holders)| holders == &vec![proposed]));
+    let res = quant.send(USERS[0], OcqAction::RenounceOwnership);
+    assert!(res.contains(&(USERS[0], err(OcqError::Unauthorized))));
+
+    // not while the rounds run, not without another admin
+    let _ = quant.send(USERS[1], OcqAction::GasReserveDefault);
+    let res = quant.send(USERS[1], OcqAction::Start);
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::Success))));
+    let res = quant.send(USERS[1], OcqAction::RenounceOwnership);
+    assert!(res.contains(&(USERS[1], err(OcqError::AlreadyRunning))));
+    let _ = quant.send(USERS[1], OcqAction::Stop);
+    let res = quant.send(USERS[1], OcqAction::RenounceOwnership);
+    assert!(res.contains(&(USERS[1], err(OcqError::LastAdmin))));
+
+    let _ = quant.send(
+        USERS[1],
+        OcqAction::GrantRole {
+            role: Role::Admin,
+            account: USERS[2].into(),
+        },
+    );
+    let res = quant.send(USERS[1], OcqAction::RenounceOwnership);
+    assert!(res.contains(&(USERS[1], ok(OcqEvent::OwnershipRenounced(proposed)))));
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.owner, ActorId::zero());
+    assert!(state.roles.iter().all(|(role, holders)| match role {
+        Role::Admin => holders == &vec![ActorId::from(USERS[2])],
+        _ => holders.is_empty(),
+    }));
+
+    // the admin left still runs the program
+    let _ = quant.send(
+        USERS[2],
+        OcqAction::GrantRole {
+            role: Role::Operator,
+            account: USERS[2].into(),
+        },
+    );
+    let _ = quant.send(USERS[2], OcqAction::GasReserveDefault);
+    let action_id = state.action_id;
+    let res = quant.send(USERS[2], OcqAction::Start);
+    assert!(res.contains(&(USERS[2], ok(OcqEvent::Success))));
+    let _ = sys.spend_blocks(2);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.action_id, action_id + 2);
+    let res = quant.send(USERS[2], OcqAction::Shutdown);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert!(res.contains(&(USERS[2], ok(OcqEvent::Shutdown(state.shutdown)))));
+}
+
+#[test]
+fn shutdown() {
+    let sys = System::new();
+    sys.init_logger();
+    let quant = Program::current(&sys);
+    let ft = init_ft(&sys);
+    init_quant(&quant, config(vec![token(USDT, actor_of(&ft), 6)]));
+    for (i, user) in USERS[1..].iter().enumerate() {
+        mint(&ft, i as u64, (*user).into(), 1_000);
+        approve(&ft, 0, *user, actor_of(&quant), 1_000);
+        invest(&quant, *user, USDT, 1_000);
+    }
+
+    let res = quant.send(USERS[0], OcqAction::Terminate);
+    assert!(res.contains(&(USERS[0], err(OcqError::ShutdownNotStarted))));
+    let res = quant.send(USERS[1], OcqAction::Shutdown);
+    assert!(res.contains(&(USERS[1], err(OcqError::Unauthorized))));
+    let res = quant.send(USERS[0], OcqAction::Shutdown);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    let ShutdownStatus::Closing {
+        since,
+        open_deposits: 2,
+        book_deposits: 0,
+        pending_transfers: 0,
+    } = state.shutdown
+    else {
+        panic!("unexpected shutdown status {:?}", state.shutdown);
+    };
+    assert!(res.contains(&(USERS[0], ok(OcqEvent::Shutdown(state.shutdown)))));
+
+    // no more rounds nor investments, but withdrawals
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let res = quant.send(USERS[0], OcqAction::Start);
+    assert!(res.contains(&(USERS[0], err(OcqError::ShuttingDown))));
+    let res = quant.send(
+        USERS[1],
+        OcqAction::Invest {
+            token: USDT.to_string(),
+            amount: 100,
+        },
+    );
+    assert!(res.contains(&(USERS[1], err(OcqError::ShuttingDown))));
+    let res = quant.send(
+        USERS[1],
+        OcqAction::WithDraw {
+            token: USDT.to_string(),
+            amount: 1_000,
+        },
+    );
+    assert!(res.contains(&(
+        USERS[1],
+        ok(OcqEvent::WithDraw {
+            token: USDT.to_string(),
+            amount: 1_000,
+        })
+    )));
+    let res = quant.send(USERS[0], OcqAction::Terminate);
+    assert!(res.contains(&(USERS[0], err(OcqError::FundsNotSettled))));
+
+    // the owner sends back what is left
+    let res = quant.send(USERS[0], OcqAction::ReturnFunds);
+    assert!(res.contains(&(
+        USERS[0],
+        ok(OcqEvent::FundsReturned {
+            returned: 1,
+            open_deposits: 0,
+        })
+    )));
+    check_balance(&ft, USERS[1].into(), 1_000);
+    check_balance(&ft, USERS[2].into(), 1_000);
+    check_balance(&ft, actor_of(&quant), 0);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.shutdown, ShutdownStatus::Settled { since });
+
+    let res = quant.send(USERS[1], OcqAction::Terminate);
+    assert!(res.contains(&(USERS[1], err(OcqError::Unauthorized))));
+    let res = quant.send(USERS[0], OcqAction::Terminate);
+    assert!(!res.main_failed());
+}
+
+#[test]
+fn shutdown_book_balances() {
+    let sys = System::new();
+    // bought at the oracle price, no ft program backs the BTC
+    let (quant, oracle, usdt) =
+        init_with_oracle_config(&sys, token(BTC, ActorId::zero(), 8), |config| config);
+    let _ = quant.send(USERS[0], OcqAction::GasReserveDefault);
+    let _ = quant.send(USERS[0], OcqAction::Start);
+    assert_eq!(assets(&quant, USERS[1])[BTC], 400_000);
+
+    let _ = quant.send(USERS[0], OcqAction::Shutdown);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    let ShutdownStatus::Closing {
+        since,
+        open_deposits: 1,
+        book_deposits: 1,
+        pending_transfers: 0,
+    } = state.shutdown
+    else {
+        panic!("unexpected shutdown status {:?}", state.shutdown);
+    };
+    let res = quant.send(USERS[0], OcqAction::Terminate);
+    assert!(res.contains(&(USERS[0], err(OcqError::FundsNotSettled))));
+
+    // the BTC goes back to USDT at the last accepted price, whatever the oracle says now
+    let _ = oracle.send(
+        USERS[0],
+        OracleAction::SetPrice {
+            token: BTC.to_string(),
+            price: 30_000 * 1_000_000,
+        },
+    );
+    let res = quant.send(USERS[0], OcqAction::ReturnFunds);
+    assert!(res.contains(&(
+        USERS[0],
+        ok(OcqEvent::FundsReturned {
+            returned: 1,
+            open_deposits: 0,
+        })
+    )));
+    check_balance(&usdt, USERS[1].into(), 1_000 * 1_000_000);
+    check_balance(&usdt, actor_of(&quant), 0);
+    let state = quant.read_state::<IOOnchainQuant>().expect("state");
+    assert_eq!(state.shutdown, ShutdownStatus::Settled { since });
+    let res = quant.send(USERS[0], OcqAction::Terminate);
+    assert!(!res.main_failed());
+}